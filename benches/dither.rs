@@ -0,0 +1,52 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+
+fn test_image(width: u32, height: u32) -> image::Rgba32FImage {
+    image::Rgba32FImage::from_fn(width, height, |x, y| {
+        let (u, v) = (x as f32 / width as f32, y as f32 / height as f32);
+        image::Rgba([u, v, ((u * 12.0).sin() * (v * 9.0).cos()) * 0.5 + 0.5, 1.0])
+    })
+}
+
+fn error_diffusion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("error_diffusion");
+    group.sample_size(10);
+
+    let image_buffer = test_image(1024, 1024);
+    let serial_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+
+    for dither in [
+        bgftool::dither::DitherOptions::FloydSteinberg,
+        bgftool::dither::DitherOptions::Stucki,
+        bgftool::dither::DitherOptions::JavisJudiceNinke,
+    ] {
+        let options = bgftool::bgf::BitmapImageOptions {
+            dither,
+            ..Default::default()
+        };
+
+        group.bench_with_input(
+            BenchmarkId::new("serial", format!("{:?}", dither)),
+            &options,
+            |b, options| {
+                b.iter(|| {
+                    serial_pool
+                        .install(|| bgftool::bgf::Bitmap::quantize(&image_buffer, options))
+                        .unwrap()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("wavefront", format!("{:?}", dither)),
+            &options,
+            |b, options| b.iter(|| bgftool::bgf::Bitmap::quantize(&image_buffer, options).unwrap()),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, error_diffusion);
+criterion_main!(benches);
@@ -0,0 +1,179 @@
+// Helpers for working out a bitmap's anchor point, the pixel that should end
+// up at the object's position once drawn. The offset is then the negated
+// anchor, so the top left corner is placed relative to it.
+
+// Center of the bottom row of the opaque area shared by all of the images.
+// Each image comes with where its top left sits in the frame, and the anchor is
+// in frame space too.
+pub fn bottom_center(
+    images: &[(&image::Rgba32FImage, (i32, i32))],
+    transparency_clip: f32,
+) -> Option<(i32, i32)> {
+    let mut bounds: Option<(i32, i32, i32, i32)> = None;
+
+    for (image_buffer, origin) in images {
+        for (x, y, _) in image_buffer
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| is_opaque(pixel, transparency_clip))
+        {
+            let (x, y) = (origin.0 + x as i32, origin.1 + y as i32);
+            bounds = Some(match bounds {
+                Some((left, top, right, bottom)) => {
+                    (left.min(x), top.min(y), right.max(x), bottom.max(y))
+                }
+                None => (x, y, x, y),
+            });
+        }
+    }
+
+    bounds.map(|(left, _, right, bottom)| ((left + right + 1).div_euclid(2), bottom))
+}
+
+// The lowest opaque pixels of all of the images, using the middle of them if
+// there is more than one. Positions are in frame space, like bottom_center.
+pub fn feet(
+    images: &[(&image::Rgba32FImage, (i32, i32))],
+    transparency_clip: f32,
+) -> Option<(i32, i32)> {
+    let bottom = images
+        .iter()
+        .flat_map(|(image_buffer, origin)| {
+            image_buffer
+                .enumerate_pixels()
+                .filter(|(_, _, pixel)| is_opaque(pixel, transparency_clip))
+                .map(|(_, y, _)| origin.1 + y as i32)
+        })
+        .max()?;
+    let columns = images
+        .iter()
+        .filter_map(|(image_buffer, origin)| {
+            u32::try_from(bottom - origin.1)
+                .ok()
+                .filter(|y| *y < image_buffer.height())
+                .map(|y| (image_buffer, origin, y))
+        })
+        .flat_map(|(image_buffer, origin, y)| {
+            (0..image_buffer.width())
+                .filter(move |x| is_opaque(image_buffer.get_pixel(*x, y), transparency_clip))
+                .map(move |x| origin.0 + x as i32)
+        })
+        .collect::<Vec<_>>();
+    let x = columns.iter().sum::<i32>() as f32 / columns.len() as f32;
+
+    Some((x.round() as i32, bottom))
+}
+
+// Find the pixels that are exactly the marker color, and paint over them with
+// their surroundings so the marker doesn't show up in the sprite. Indices that
+// were read in as they are get the same treatment, using the index of a
+// neighbor that isn't part of the marker. Returns the middle of the marker.
+pub fn take_marker(
+    image_buffer: &mut image::Rgba32FImage,
+    indices: Option<&mut [u8]>,
+    color: [u8; 3],
+) -> Option<(i32, i32)> {
+    let is_marker = |pixel: &image::Rgba<f32>| {
+        pixel[3] > 0.0
+            && (0..3).all(|i| (pixel[i].clamp(0.0, 1.0) * 255.0).round() as u8 == color[i])
+    };
+    let markers = image_buffer
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| is_marker(pixel))
+        .map(|(x, y, _)| (x, y))
+        .collect::<Vec<_>>();
+
+    if markers.is_empty() {
+        return None;
+    }
+
+    if let Some(indices) = indices {
+        let width = image_buffer.width() as usize;
+        let (transparent_index, _) = crate::bgf::Palette::new().transparent_color();
+        let marked = markers
+            .iter()
+            .map(|(x, y)| *y as usize * width + *x as usize)
+            .collect::<std::collections::HashSet<_>>();
+
+        for index in marked.iter().copied() {
+            let (x, y) = (index % width, index / width);
+            let neighbor = [
+                (x > 0).then(|| index - 1),
+                (x + 1 < width).then_some(index + 1),
+                (y > 0).then(|| index - width),
+                Some(index + width).filter(|i| *i < indices.len()),
+            ]
+            .into_iter()
+            .flatten()
+            .find(|i| !marked.contains(i));
+
+            indices[index] = neighbor.map_or(transparent_index as u8, |i| indices[i]);
+        }
+    }
+
+    for (x, y) in &markers {
+        let mut sum = [0.0f32; 4];
+        let mut count = 0;
+
+        for (dx, dy) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+            let (nx, ny) = (*x as i64 + dx, *y as i64 + dy);
+
+            if nx < 0
+                || ny < 0
+                || nx >= image_buffer.width() as i64
+                || ny >= image_buffer.height() as i64
+            {
+                continue;
+            }
+
+            let neighbor = image_buffer.get_pixel(nx as u32, ny as u32);
+
+            if !is_marker(neighbor) {
+                (0..4).for_each(|i| sum[i] += neighbor[i]);
+                count += 1;
+            }
+        }
+
+        let pixel = if count == 0 {
+            image::Rgba([0.0; 4])
+        } else {
+            image::Rgba(sum.map(|v| v / count as f32))
+        };
+        image_buffer.put_pixel(*x, *y, pixel);
+    }
+
+    let count = markers.len() as f32;
+    let x = markers.iter().map(|(x, _)| *x as f32).sum::<f32>() / count;
+    let y = markers.iter().map(|(_, y)| *y as f32).sum::<f32>() / count;
+
+    Some((x.round() as i32, y.round() as i32))
+}
+
+#[inline(always)]
+fn is_opaque(pixel: &image::Rgba<f32>, transparency_clip: f32) -> bool {
+    pixel[3] > 0.0 && pixel[3] >= transparency_clip
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn take_marker_clears_indices() {
+        let marker = [255, 0, 255];
+        let mut image_buffer = image::Rgba32FImage::from_fn(4, 3, |x, y| match (x, y) {
+            (1, 1) | (2, 1) => image::Rgba([1.0, 0.0, 1.0, 1.0]),
+            _ => image::Rgba([0.5, 0.5, 0.5, 1.0]),
+        });
+        let mut indices = (0..12).collect::<Vec<u8>>();
+
+        let position = super::take_marker(&mut image_buffer, Some(&mut indices), marker);
+
+        assert_eq!(position, Some((2, 1)));
+        // Each marker pixel takes the index of its first neighbor (left, right,
+        // above, below) that isn't part of the marker.
+        assert_eq!(indices, [0, 1, 2, 3, 4, 4, 7, 7, 8, 9, 10, 11]);
+        assert!(
+            image_buffer
+                .pixels()
+                .all(|pixel| *pixel == image::Rgba([0.5, 0.5, 0.5, 1.0]))
+        );
+    }
+}
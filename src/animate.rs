@@ -0,0 +1,391 @@
+use std::io::prelude::*;
+
+use color_eyre::eyre::{self, Result};
+
+// Playing back index groups as animated images, and reading animated images
+// back in as bitmaps. Every frame is drawn on a canvas big enough for all of
+// the group's bitmaps, placed by their offsets.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AnimationFormat {
+    Gif,
+    Apng,
+    Webp,
+}
+
+impl AnimationFormat {
+    pub fn from_path(path: &std::path::Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+
+        match extension.as_str() {
+            "gif" => Some(Self::Gif),
+            "png" | "apng" => Some(Self::Apng),
+            "webp" => Some(Self::Webp),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationOptions {
+    // How long each frame is shown for, in milliseconds.
+    pub delay: u16,
+    pub scale: u32,
+    // Color to fill transparent pixels with. Without one they stay
+    // transparent.
+    pub background: Option<[u8; 3]>,
+}
+
+impl Default for AnimationOptions {
+    fn default() -> Self {
+        Self {
+            delay: 100,
+            scale: 1,
+            background: None,
+        }
+    }
+}
+
+// Draw every frame of a group onto a shared canvas.
+pub fn render_group(
+    bgf: &crate::bgf::Bgf,
+    group: &crate::bgf::Group,
+    options: &AnimationOptions,
+) -> Result<Vec<image::RgbaImage>> {
+    crate::compose::compose(bgf, group, &[], options)
+}
+
+// Write the frames as a looping animation.
+pub fn write<W: Write>(
+    writer: W,
+    frames: &[image::RgbaImage],
+    delay: u16,
+    format: AnimationFormat,
+) -> Result<()> {
+    if frames.is_empty() {
+        return Err(eyre::eyre!("An animation needs at least one frame."));
+    }
+
+    match format {
+        AnimationFormat::Gif => write_gif(writer, frames, delay),
+        AnimationFormat::Apng => write_apng(writer, frames, delay),
+        AnimationFormat::Webp => write_webp(writer, frames, delay),
+    }
+}
+
+fn write_gif<W: Write>(writer: W, frames: &[image::RgbaImage], delay: u16) -> Result<()> {
+    let mut encoder = image::codecs::gif::GifEncoder::new(writer);
+    encoder.set_repeat(image::codecs::gif::Repeat::Infinite)?;
+    encoder.encode_frames(frames.iter().map(|frame| {
+        image::Frame::from_parts(
+            frame.clone(),
+            0,
+            0,
+            image::Delay::from_numer_denom_ms(delay as u32, 1),
+        )
+    }))?;
+
+    Ok(())
+}
+
+fn write_apng<W: Write>(writer: W, frames: &[image::RgbaImage], delay: u16) -> Result<()> {
+    let (width, height) = frames[0].dimensions();
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    // Zero plays loops forever.
+    encoder.set_animated(frames.len() as u32, 0)?;
+    encoder.set_frame_delay(delay, 1000)?;
+
+    let mut writer = encoder.write_header()?;
+
+    for frame in frames {
+        writer.write_image_data(frame.as_raw())?;
+    }
+
+    writer.finish()?;
+
+    Ok(())
+}
+
+// The image crate only writes still WebPs, so each frame is encoded on its own
+// and the bitstreams are wrapped in the animation chunks by hand. See
+// https://developers.google.com/speed/webp/docs/riff_container
+fn write_webp<W: Write>(mut writer: W, frames: &[image::RgbaImage], delay: u16) -> Result<()> {
+    const FLAG_ANIMATION: u8 = 0x02;
+    const FLAG_ALPHA: u8 = 0x10;
+    // Frames cover the whole canvas, so there's nothing to blend with.
+    const FRAME_NO_BLEND: u8 = 0x02;
+
+    let (width, height) = frames[0].dimensions();
+
+    if width > 1 << 24 || height > 1 << 24 {
+        return Err(eyre::eyre!(
+            "WebP animations can't be bigger than 16777216x16777216."
+        ));
+    }
+
+    let mut body = Vec::new();
+
+    let mut vp8x = vec![FLAG_ANIMATION | FLAG_ALPHA, 0, 0, 0];
+    vp8x.extend_from_slice(&u24(width - 1));
+    vp8x.extend_from_slice(&u24(height - 1));
+    write_chunk(&mut body, b"VP8X", &vp8x)?;
+
+    // Transparent background, looping forever.
+    let mut anim = 0u32.to_le_bytes().to_vec();
+    anim.extend_from_slice(&0u16.to_le_bytes());
+    write_chunk(&mut body, b"ANIM", &anim)?;
+
+    for frame in frames {
+        let mut still = Vec::new();
+        image::codecs::webp::WebPEncoder::new_lossless(&mut still).encode(
+            frame.as_raw(),
+            width,
+            height,
+            image::ExtendedColorType::Rgba8,
+        )?;
+
+        let mut anmf = Vec::new();
+        anmf.extend_from_slice(&u24(0));
+        anmf.extend_from_slice(&u24(0));
+        anmf.extend_from_slice(&u24(width - 1));
+        anmf.extend_from_slice(&u24(height - 1));
+        anmf.extend_from_slice(&u24(delay as u32));
+        anmf.push(FRAME_NO_BLEND);
+
+        // Keep the image data, and drop the still image's own header.
+        for (fourcc, data) in riff_chunks(&still)? {
+            if matches!(&fourcc, b"ALPH" | b"VP8 " | b"VP8L") {
+                write_chunk(&mut anmf, &fourcc, data)?;
+            }
+        }
+
+        write_chunk(&mut body, b"ANMF", &anmf)?;
+    }
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(body.len() as u32 + 4).to_le_bytes())?;
+    writer.write_all(b"WEBP")?;
+    writer.write_all(&body)?;
+
+    Ok(())
+}
+
+// The chunks of a WebP file.
+fn riff_chunks(data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err(eyre::eyre!("Encoded WebP is invalid."));
+    }
+
+    let mut chunks = Vec::new();
+    let mut rest = &data[12..];
+
+    while rest.len() >= 8 {
+        let fourcc = [rest[0], rest[1], rest[2], rest[3]];
+        let len = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        let padded = len + len % 2;
+
+        if rest.len() < 8 + len {
+            return Err(eyre::eyre!("Encoded WebP is invalid."));
+        }
+
+        chunks.push((fourcc, &rest[8..8 + len]));
+        rest = &rest[(8 + padded).min(rest.len())..];
+    }
+
+    Ok(chunks)
+}
+
+fn write_chunk<W: Write>(mut writer: W, fourcc: &[u8; 4], data: &[u8]) -> Result<()> {
+    writer.write_all(fourcc)?;
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(data)?;
+
+    // Chunks are padded to an even length.
+    if data.len() % 2 == 1 {
+        writer.write_all(&[0])?;
+    }
+
+    Ok(())
+}
+
+#[inline(always)]
+fn u24(value: u32) -> [u8; 3] {
+    let bytes = value.to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+pub fn is_animation(path: &std::path::Path) -> bool {
+    AnimationFormat::from_path(path).is_some()
+}
+
+// Every frame of an animated image, drawn in full with the disposal and
+// blending of the frames before it already applied. Still images come back as
+// a single frame.
+pub fn read_frames(path: &std::path::Path) -> Result<Vec<image::Rgba32FImage>> {
+    let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let frames = match image::ImageFormat::from_path(path)? {
+        image::ImageFormat::Gif => {
+            image::AnimationDecoder::into_frames(image::codecs::gif::GifDecoder::new(reader)?)
+                .collect_frames()?
+        }
+        image::ImageFormat::Png => {
+            let decoder = image::codecs::png::PngDecoder::new(reader)?;
+
+            if !decoder.is_apng()? {
+                return Ok(vec![crate::bgf::Bitmap::open_image(path)?]);
+            }
+
+            image::AnimationDecoder::into_frames(decoder.apng()?).collect_frames()?
+        }
+        image::ImageFormat::WebP => {
+            let decoder = image::codecs::webp::WebPDecoder::new(reader)?;
+
+            if !decoder.has_animation() {
+                return Ok(vec![crate::bgf::Bitmap::open_image(path)?]);
+            }
+
+            image::AnimationDecoder::into_frames(decoder).collect_frames()?
+        }
+        _ => return Ok(vec![crate::bgf::Bitmap::open_image(path)?]),
+    };
+
+    Ok(frames
+        .into_iter()
+        .map(|frame| image::DynamicImage::from(frame.into_buffer()).into_rgba32f())
+        .collect())
+}
+
+// Turn an animated image into a bitmap per frame, all in one group. Each bitmap
+// only covers the part of its frame with anything in it, and is offset by
+// where that part sits on the canvas. This isn't the rect the file gives the
+// frame: a BGF frame is drawn on its own, so it has to hold everything left
+// over from earlier frames too, and nothing transparent around it.
+pub fn slice(
+    sheet: &crate::conf::Sheet,
+    conf_dir: &std::path::Path,
+) -> Result<(Vec<crate::conf::Bitmap>, Vec<Vec<usize>>)> {
+    let frames = read_frames(&conf_dir.join(&sheet.path))?;
+    let bitmaps = frames
+        .iter()
+        .enumerate()
+        .map(|(frame, image_buffer)| {
+            let rect = opaque_bounds(image_buffer).unwrap_or(crate::atlas::Rect {
+                x: 0,
+                y: 0,
+                w: image_buffer.width(),
+                h: image_buffer.height(),
+            });
+
+            crate::conf::Bitmap {
+                size: (rect.w as i32, rect.h as i32),
+                offset: (
+                    sheet.offset.0 + rect.x as i32,
+                    sheet.offset.1 + rect.y as i32,
+                ),
+                hotspots: Vec::new(),
+                compression: sheet.compression,
+                path: sheet.path.clone(),
+                dither: sheet.dither.clone(),
+                dither_mask: None,
+                anchor: sheet.anchor,
+                hotspot_image: None,
+                hotspot_markers: Vec::new(),
+                rect: Some(rect),
+                origin: Some((rect.x as i32, rect.y as i32)),
+                frame: Some(frame),
+                hotspot_layer: None,
+            }
+        })
+        .collect::<Vec<_>>();
+    let groups = vec![(0..bitmaps.len()).collect()];
+
+    Ok((bitmaps, groups))
+}
+
+fn opaque_bounds(image_buffer: &image::Rgba32FImage) -> Option<crate::atlas::Rect> {
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+
+    for (x, y, _) in image_buffer
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel[3] > 0.0)
+    {
+        bounds = Some(match bounds {
+            Some((left, top, right, bottom)) => {
+                (left.min(x), top.min(y), right.max(x), bottom.max(y))
+            }
+            None => (x, y, x, y),
+        });
+    }
+
+    bounds.map(|(left, top, right, bottom)| crate::atlas::Rect {
+        x: left,
+        y: top,
+        w: right - left + 1,
+        h: bottom - top + 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_rejects_no_frames() {
+        for format in [
+            AnimationFormat::Gif,
+            AnimationFormat::Apng,
+            AnimationFormat::Webp,
+        ] {
+            assert!(write(Vec::new(), &[], 100, format).is_err());
+        }
+    }
+
+    #[test]
+    fn slice_disposal_and_offsets() {
+        let sheet = crate::conf::Sheet {
+            path: "disposal.gif".into(),
+            grid: None,
+            manifest: None,
+            offset: (-8, -12),
+            compression: Default::default(),
+            dither: None,
+            anchor: None,
+            hotspot_layer: None,
+        };
+        let (bitmaps, groups) = slice(
+            &sheet,
+            std::path::Path::new(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/animate"
+            )),
+        )
+        .unwrap();
+        let rects = bitmaps
+            .iter()
+            .map(|bitmap| {
+                let rect = bitmap.rect.as_ref().unwrap();
+
+                (rect.x, rect.y, rect.w, rect.h)
+            })
+            .collect::<Vec<_>>();
+        let offsets = bitmaps
+            .iter()
+            .map(|bitmap| bitmap.offset)
+            .collect::<Vec<_>>();
+
+        // The first frame is cleared before the second, whose transparent
+        // border is cropped, and the second stays under the third.
+        assert_eq!(rects, [(2, 3, 4, 4), (8, 5, 3, 2), (0, 0, 11, 7)]);
+        assert_eq!(offsets, [(-6, -9), (0, -7), (-8, -12)]);
+        assert_eq!(
+            bitmaps
+                .iter()
+                .map(|bitmap| (bitmap.size, bitmap.frame))
+                .collect::<Vec<_>>(),
+            [((4, 4), Some(0)), ((3, 2), Some(1)), ((11, 7), Some(2))]
+        );
+        assert_eq!(groups, [vec![0, 1, 2]]);
+    }
+}
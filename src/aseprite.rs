@@ -0,0 +1,815 @@
+use std::io::prelude::*;
+
+use color_eyre::eyre::{self, Result};
+
+// Reading Aseprite documents, following
+// https://github.com/aseprite/aseprite/blob/main/docs/ase-file-specs.md
+// Only what's needed to draw the frames is kept: layers, cels, tags, slices
+// and the palette. Everything in the file is little endian.
+
+const HEADER_MAGIC: u16 = 0xA5E0;
+const FRAME_MAGIC: u16 = 0xF1FA;
+
+const CHUNK_LAYER: u16 = 0x2004;
+const CHUNK_CEL: u16 = 0x2005;
+const CHUNK_TAGS: u16 = 0x2018;
+const CHUNK_PALETTE: u16 = 0x2019;
+const CHUNK_SLICE: u16 = 0x2022;
+
+const LAYER_VISIBLE: u16 = 1;
+const SLICE_NINE_PATCH: u32 = 1;
+const SLICE_PIVOT: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorDepth {
+    Rgba,
+    Grayscale,
+    Indexed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerType {
+    Normal,
+    Group,
+    Tilemap,
+}
+
+#[derive(Debug)]
+pub struct Layer {
+    pub name: String,
+    pub flags: u16,
+    pub layer_type: LayerType,
+    pub child_level: u16,
+    pub opacity: u8,
+}
+
+#[derive(Debug)]
+pub enum CelData {
+    Image {
+        size: (u32, u32),
+        // Raw pixels in the document's color depth.
+        pixels: Vec<u8>,
+    },
+    // Same as the cel on this layer in another frame.
+    Linked(usize),
+}
+
+#[derive(Debug)]
+pub struct Cel {
+    pub layer: usize,
+    pub position: (i32, i32),
+    pub opacity: u8,
+    pub z_index: i16,
+    pub data: CelData,
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub duration: u16,
+    pub cels: Vec<Cel>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Reverse,
+    PingPong,
+    PingPongReverse,
+}
+
+#[derive(Debug)]
+pub struct Tag {
+    pub name: String,
+    pub from: usize,
+    pub to: usize,
+    pub direction: Direction,
+}
+
+#[derive(Debug)]
+pub struct SliceKey {
+    // The key applies from this frame on, until the next key.
+    pub frame: usize,
+    pub position: (i32, i32),
+    pub size: (u32, u32),
+    // Relative to the slice's position.
+    pub pivot: Option<(i32, i32)>,
+}
+
+#[derive(Debug)]
+pub struct Slice {
+    pub name: String,
+    pub keys: Vec<SliceKey>,
+}
+
+#[derive(Debug)]
+pub struct Document {
+    pub size: (u32, u32),
+    pub color_depth: ColorDepth,
+    // Only used by indexed documents.
+    pub transparent_index: u8,
+    pub palette: Vec<[u8; 4]>,
+    pub layers: Vec<Layer>,
+    pub frames: Vec<Frame>,
+    pub tags: Vec<Tag>,
+    pub slices: Vec<Slice>,
+}
+
+impl Document {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Self::read(std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<Self> {
+        let mut header = [0u8; 128];
+        reader.read_exact(&mut header)?;
+        let mut header = &header[..];
+
+        // File size
+        read_u32(&mut header)?;
+
+        if read_u16(&mut header)? != HEADER_MAGIC {
+            return Err(eyre::eyre!("Not an Aseprite file."));
+        }
+
+        let frame_count = read_u16(&mut header)?;
+        let width = read_u16(&mut header)?;
+        let height = read_u16(&mut header)?;
+        let color_depth = match read_u16(&mut header)? {
+            32 => ColorDepth::Rgba,
+            16 => ColorDepth::Grayscale,
+            8 => ColorDepth::Indexed,
+            depth => return Err(eyre::eyre!("Invalid Aseprite color depth {depth}.")),
+        };
+
+        // Flags, speed and two reserved values
+        skip(&mut header, 4 + 2 + 4 + 4)?;
+        let transparent_index = read_u8(&mut header)?;
+
+        let mut document = Self {
+            size: (width as u32, height as u32),
+            color_depth,
+            transparent_index,
+            palette: Vec::new(),
+            layers: Vec::new(),
+            frames: Vec::with_capacity(frame_count as usize),
+            tags: Vec::new(),
+            slices: Vec::new(),
+        };
+
+        for _ in 0..frame_count {
+            let frame_size = read_u32(&mut reader)? as usize;
+            let mut frame_bytes = vec![0u8; frame_size.saturating_sub(4)];
+            reader.read_exact(&mut frame_bytes)?;
+            let frame = document.read_frame(&frame_bytes[..])?;
+            document.frames.push(frame);
+        }
+
+        Ok(document)
+    }
+
+    fn read_frame(&mut self, mut reader: &[u8]) -> Result<Frame> {
+        if read_u16(&mut reader)? != FRAME_MAGIC {
+            return Err(eyre::eyre!("Aseprite frame magic number is invalid."));
+        }
+
+        let old_chunk_count = read_u16(&mut reader)?;
+        let duration = read_u16(&mut reader)?;
+        skip(&mut reader, 2)?;
+        let chunk_count = match read_u32(&mut reader)? {
+            0 => old_chunk_count as u32,
+            count => count,
+        };
+        let mut frame = Frame {
+            duration,
+            cels: Vec::new(),
+        };
+
+        for _ in 0..chunk_count {
+            let chunk_size = read_u32(&mut reader)? as usize;
+            let chunk_type = read_u16(&mut reader)?;
+            let data_size = chunk_size
+                .checked_sub(6)
+                .filter(|size| *size <= reader.len())
+                .ok_or_else(|| eyre::eyre!("Aseprite chunk size is invalid."))?;
+            let (data, rest) = reader.split_at(data_size);
+            reader = rest;
+
+            match chunk_type {
+                CHUNK_LAYER => self.layers.push(read_layer(data)?),
+                CHUNK_CEL => frame.cels.push(self.read_cel(data)?),
+                CHUNK_TAGS => self.tags = read_tags(data)?,
+                CHUNK_PALETTE => self.read_palette(data)?,
+                CHUNK_SLICE => self.slices.push(read_slice(data)?),
+                // Anything else doesn't change how the frames look.
+                _ => {}
+            }
+        }
+
+        Ok(frame)
+    }
+
+    fn read_cel(&self, mut reader: &[u8]) -> Result<Cel> {
+        let layer = read_u16(&mut reader)? as usize;
+        let x = read_u16(&mut reader)? as i16;
+        let y = read_u16(&mut reader)? as i16;
+        let opacity = read_u8(&mut reader)?;
+        let cel_type = read_u16(&mut reader)?;
+        let z_index = read_u16(&mut reader)? as i16;
+        skip(&mut reader, 5)?;
+
+        let data = match cel_type {
+            0 | 2 => {
+                let width = read_u16(&mut reader)? as u32;
+                let height = read_u16(&mut reader)? as u32;
+                let len = (width * height) as usize * self.bytes_per_pixel();
+                let mut pixels = Vec::with_capacity(len);
+
+                if cel_type == 0 {
+                    pixels.extend_from_slice(reader);
+                } else {
+                    flate2::read::ZlibDecoder::new(reader).read_to_end(&mut pixels)?;
+                }
+
+                if pixels.len() < len {
+                    return Err(eyre::eyre!("Aseprite cel is missing pixels."));
+                }
+
+                pixels.truncate(len);
+                CelData::Image {
+                    size: (width, height),
+                    pixels,
+                }
+            }
+            1 => CelData::Linked(read_u16(&mut reader)? as usize),
+            _ => return Err(eyre::eyre!("Aseprite tilemaps aren't supported.")),
+        };
+
+        Ok(Cel {
+            layer,
+            position: (x as i32, y as i32),
+            opacity,
+            z_index,
+            data,
+        })
+    }
+
+    fn read_palette(&mut self, mut reader: &[u8]) -> Result<()> {
+        let size = read_u32(&mut reader)? as usize;
+        let first = read_u32(&mut reader)? as usize;
+        let last = read_u32(&mut reader)? as usize;
+        skip(&mut reader, 8)?;
+
+        if first > last || last >= size {
+            return Err(eyre::eyre!("Aseprite palette range is invalid."));
+        }
+
+        self.palette.resize(size, [0, 0, 0, 255]);
+
+        for entry in &mut self.palette[first..=last] {
+            let flags = read_u16(&mut reader)?;
+            reader.read_exact(entry)?;
+
+            // Has a name
+            if flags & 1 != 0 {
+                read_string(&mut reader)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        match self.color_depth {
+            ColorDepth::Rgba => 4,
+            ColorDepth::Grayscale => 2,
+            ColorDepth::Indexed => 1,
+        }
+    }
+
+    // Whether each layer is shown, taking the groups it's in into account.
+    fn visible_layers(&self) -> Vec<bool> {
+        let mut group_visible = Vec::new();
+
+        self.layers
+            .iter()
+            .map(|layer| {
+                let level = layer.child_level as usize;
+                let visible = layer.flags & LAYER_VISIBLE != 0
+                    && (level == 0 || group_visible.get(level - 1).copied().unwrap_or(true));
+                group_visible.truncate(level);
+                group_visible.push(visible);
+
+                visible
+            })
+            .collect()
+    }
+
+    pub fn layer_index(&self, name: &str) -> Option<usize> {
+        self.layers.iter().position(|layer| layer.name == name)
+    }
+
+    // The cels to draw for a frame, bottom to top. Linked cels are swapped for
+    // the cel they share, which also has their position and opacity.
+    fn cels(&self, frame: usize, layers: &[bool]) -> Vec<&Cel> {
+        let mut cels = self.frames[frame]
+            .cels
+            .iter()
+            .filter(|cel| layers.get(cel.layer).copied().unwrap_or(false))
+            .filter_map(|cel| {
+                let shared = match &cel.data {
+                    CelData::Linked(linked_frame) => self
+                        .frames
+                        .get(*linked_frame)?
+                        .cels
+                        .iter()
+                        .find(|linked| linked.layer == cel.layer)?,
+                    _ => cel,
+                };
+                let order = (cel.layer as i64 + cel.z_index as i64, cel.z_index);
+
+                matches!(shared.data, CelData::Image { .. }).then_some((order, shared))
+            })
+            .collect::<Vec<_>>();
+        cels.sort_by_key(|(order, _)| *order);
+
+        cels.into_iter().map(|(_, cel)| cel).collect()
+    }
+
+    // Draw a frame, using every visible layer except `skip_layer`.
+    pub fn render(&self, frame: usize, skip_layer: Option<usize>) -> image::Rgba32FImage {
+        let mut layers = self.visible_layers();
+
+        if let Some(skip_layer) = skip_layer.and_then(|i| layers.get_mut(i)) {
+            *skip_layer = false;
+        }
+
+        self.render_layers(frame, &layers)
+    }
+
+    // Draw a single layer of a frame, whether it's shown or not.
+    pub fn render_layer(&self, frame: usize, layer: usize) -> image::Rgba32FImage {
+        let layers = (0..self.layers.len())
+            .map(|i| i == layer)
+            .collect::<Vec<_>>();
+
+        self.render_layers(frame, &layers)
+    }
+
+    fn render_layers(&self, frame: usize, layers: &[bool]) -> image::Rgba32FImage {
+        let mut image_buffer = image::Rgba32FImage::new(self.size.0, self.size.1);
+
+        for cel in self.cels(frame, layers) {
+            let CelData::Image { size, pixels } = &cel.data else {
+                continue;
+            };
+            let layer = &self.layers[cel.layer];
+            let opacity = cel.opacity as f32 / 255.0 * layer.opacity as f32 / 255.0;
+
+            self.for_each_pixel(cel, *size, |x, y, i| {
+                let [r, g, b, a] = self.color(pixels, i);
+                let source_alpha = a as f32 / 255.0 * opacity;
+                let destination = image_buffer.get_pixel_mut(x, y);
+                let alpha = source_alpha + destination[3] * (1.0 - source_alpha);
+
+                if alpha > 0.0 {
+                    for (c, value) in [r, g, b].into_iter().enumerate() {
+                        destination[c] = (value as f32 / 255.0 * source_alpha
+                            + destination[c] * destination[3] * (1.0 - source_alpha))
+                            / alpha;
+                    }
+                }
+
+                destination[3] = alpha;
+            });
+        }
+
+        image_buffer
+    }
+
+    // Whether the palette lines up with the BGF palette, so indices can be
+    // used as they are.
+    pub fn uses_bgf_palette(&self) -> bool {
+        let palette = crate::bgf::Palette::new();
+
+        self.color_depth == ColorDepth::Indexed
+            && !self.palette.is_empty()
+            && self.palette.len() <= palette.values().len()
+            && self
+                .palette
+                .iter()
+                .zip(palette.values())
+                .all(|(color, bgf_color)| color[..3] == bgf_color.0)
+    }
+
+    // The palette indices of a frame, for indexed documents that use the BGF
+    // palette. The topmost opaque pixel wins, so opacity isn't blended.
+    pub fn render_indices(&self, frame: usize, skip_layer: Option<usize>) -> Option<Vec<u8>> {
+        if !self.uses_bgf_palette() {
+            return None;
+        }
+
+        let (transparent_index, _) = crate::bgf::Palette::new().transparent_color();
+        let mut layers = self.visible_layers();
+
+        if let Some(skip_layer) = skip_layer.and_then(|i| layers.get_mut(i)) {
+            *skip_layer = false;
+        }
+
+        let mut indices = vec![transparent_index as u8; (self.size.0 * self.size.1) as usize];
+
+        for cel in self.cels(frame, &layers) {
+            let CelData::Image { size, pixels } = &cel.data else {
+                continue;
+            };
+
+            // The document's transparent color is left as the BGF's, whatever
+            // index it's at, even on the background layer.
+            self.for_each_pixel(cel, *size, |x, y, i| {
+                if pixels[i] != self.transparent_index {
+                    indices[(y * self.size.0 + x) as usize] = pixels[i];
+                }
+            });
+        }
+
+        Some(indices)
+    }
+
+    // Visit each pixel of a cel that lands on the canvas, with its canvas
+    // position and its index in the cel.
+    fn for_each_pixel<F>(&self, cel: &Cel, size: (u32, u32), mut f: F)
+    where
+        F: FnMut(u32, u32, usize),
+    {
+        for cy in 0..size.1 {
+            for cx in 0..size.0 {
+                let (x, y) = (cel.position.0 + cx as i32, cel.position.1 + cy as i32);
+
+                if x >= 0 && y >= 0 && (x as u32) < self.size.0 && (y as u32) < self.size.1 {
+                    f(x as u32, y as u32, (cy * size.0 + cx) as usize);
+                }
+            }
+        }
+    }
+
+    fn color(&self, pixels: &[u8], i: usize) -> [u8; 4] {
+        match self.color_depth {
+            ColorDepth::Rgba => [
+                pixels[i * 4],
+                pixels[i * 4 + 1],
+                pixels[i * 4 + 2],
+                pixels[i * 4 + 3],
+            ],
+            ColorDepth::Grayscale => {
+                let value = pixels[i * 2];
+                [value, value, value, pixels[i * 2 + 1]]
+            }
+            ColorDepth::Indexed => {
+                let index = pixels[i];
+
+                // Aseprite draws the transparent color opaque on the background
+                // layer, but a BGF has only the one transparent index, so it's
+                // left out everywhere, the same as in `render_indices`.
+                if index == self.transparent_index {
+                    [0, 0, 0, 0]
+                } else {
+                    self.palette
+                        .get(index as usize)
+                        .copied()
+                        .unwrap_or([0, 0, 0, 255])
+                }
+            }
+        }
+    }
+
+    // The pivot of the first slice that has one in this frame, in canvas
+    // coordinates.
+    pub fn pivot(&self, frame: usize) -> Option<(i32, i32)> {
+        self.slices.iter().find_map(|slice| {
+            let key = slice.keys.iter().rfind(|key| key.frame <= frame)?;
+            let pivot = key.pivot?;
+
+            Some((key.position.0 + pivot.0, key.position.1 + pivot.1))
+        })
+    }
+
+    // The frames of a tag in the order they play, once through.
+    pub fn tag_frames(&self, tag: &Tag) -> Vec<usize> {
+        let to = tag.to.min(self.frames.len().saturating_sub(1));
+        let forward = (tag.from..=to).collect::<Vec<_>>();
+        let reverse = forward.iter().rev().copied().collect::<Vec<_>>();
+        // The ends aren't repeated when going back the other way.
+        let back = |frames: &[usize]| {
+            frames
+                .get(1..frames.len().saturating_sub(1))
+                .unwrap_or_default()
+                .iter()
+                .rev()
+                .copied()
+                .collect::<Vec<_>>()
+        };
+
+        match tag.direction {
+            Direction::Forward => forward,
+            Direction::Reverse => reverse,
+            Direction::PingPong => [forward.clone(), back(&forward)].concat(),
+            Direction::PingPongReverse => [reverse.clone(), back(&reverse)].concat(),
+        }
+    }
+}
+
+// Turn a document into bitmaps and index groups: every frame is a bitmap, and
+// each tag is a group. Without tags, all the frames are one group.
+pub fn slice(
+    sheet: &crate::conf::Sheet,
+    conf_dir: &std::path::Path,
+) -> Result<(Vec<crate::conf::Bitmap>, Vec<Vec<usize>>)> {
+    let document = Document::open(conf_dir.join(&sheet.path))?;
+
+    if let Some(layer) = &sheet.hotspot_layer
+        && document.layer_index(layer).is_none()
+    {
+        return Err(eyre::eyre!(
+            "{} has no layer called \"{}\".",
+            sheet.path.display(),
+            layer
+        ));
+    }
+
+    let bitmaps = (0..document.frames.len())
+        .map(|frame| crate::conf::Bitmap {
+            size: (document.size.0 as i32, document.size.1 as i32),
+            offset: sheet.offset,
+            hotspots: Vec::new(),
+            compression: sheet.compression,
+            path: sheet.path.clone(),
+            dither: sheet.dither.clone(),
+            dither_mask: None,
+            // A pivot set in the document wins over the sheet's anchor.
+            anchor: document
+                .pivot(frame)
+                .map(crate::conf::Anchor::Pivot)
+                .or(sheet.anchor),
+            hotspot_image: None,
+            hotspot_markers: Vec::new(),
+            rect: None,
+            origin: None,
+            frame: Some(frame),
+            hotspot_layer: sheet.hotspot_layer.clone(),
+        })
+        .collect::<Vec<_>>();
+    let groups = if document.tags.is_empty() {
+        vec![(0..bitmaps.len()).collect()]
+    } else {
+        document
+            .tags
+            .iter()
+            .map(|tag| document.tag_frames(tag))
+            .collect()
+    };
+
+    Ok((bitmaps, groups))
+}
+
+pub fn is_aseprite(path: &std::path::Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("aseprite") || extension.eq_ignore_ascii_case("ase")
+        })
+}
+
+fn read_layer(mut reader: &[u8]) -> Result<Layer> {
+    let flags = read_u16(&mut reader)?;
+    let layer_type = match read_u16(&mut reader)? {
+        0 => LayerType::Normal,
+        1 => LayerType::Group,
+        2 => LayerType::Tilemap,
+        layer_type => return Err(eyre::eyre!("Invalid Aseprite layer type {layer_type}.")),
+    };
+    let child_level = read_u16(&mut reader)?;
+    // Default width and height, and blend mode
+    skip(&mut reader, 6)?;
+    let opacity = read_u8(&mut reader)?;
+    skip(&mut reader, 3)?;
+    let name = read_string(&mut reader)?;
+
+    Ok(Layer {
+        name,
+        flags,
+        layer_type,
+        child_level,
+        opacity,
+    })
+}
+
+fn read_tags(mut reader: &[u8]) -> Result<Vec<Tag>> {
+    let count = read_u16(&mut reader)?;
+    skip(&mut reader, 8)?;
+
+    (0..count)
+        .map(|_| {
+            let from = read_u16(&mut reader)? as usize;
+            let to = read_u16(&mut reader)? as usize;
+            let direction = match read_u8(&mut reader)? {
+                1 => Direction::Reverse,
+                2 => Direction::PingPong,
+                3 => Direction::PingPongReverse,
+                _ => Direction::Forward,
+            };
+            // Repeat count, reserved bytes and the tag color
+            skip(&mut reader, 2 + 6 + 4)?;
+            let name = read_string(&mut reader)?;
+
+            Ok(Tag {
+                name,
+                from,
+                to,
+                direction,
+            })
+        })
+        .collect()
+}
+
+fn read_slice(mut reader: &[u8]) -> Result<Slice> {
+    let key_count = read_u32(&mut reader)?;
+    let flags = read_u32(&mut reader)?;
+    skip(&mut reader, 4)?;
+    let name = read_string(&mut reader)?;
+    let keys = (0..key_count)
+        .map(|_| {
+            let frame = read_u32(&mut reader)? as usize;
+            let x = read_u32(&mut reader)? as i32;
+            let y = read_u32(&mut reader)? as i32;
+            let width = read_u32(&mut reader)?;
+            let height = read_u32(&mut reader)?;
+
+            if flags & SLICE_NINE_PATCH != 0 {
+                skip(&mut reader, 16)?;
+            }
+
+            let pivot = if flags & SLICE_PIVOT != 0 {
+                Some((read_u32(&mut reader)? as i32, read_u32(&mut reader)? as i32))
+            } else {
+                None
+            };
+
+            Ok(SliceKey {
+                frame,
+                position: (x, y),
+                size: (width, height),
+                pivot,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Slice { name, keys })
+}
+
+fn read_u8(reader: &mut &[u8]) -> Result<u8> {
+    let mut bytes = [0u8; 1];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(bytes[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_string(reader: &mut &[u8]) -> Result<String> {
+    let len = read_u16(reader)? as usize;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn skip(reader: &mut &[u8], len: usize) -> Result<()> {
+    if reader.len() < len {
+        return Err(eyre::eyre!("Unexpected end of Aseprite data."));
+    }
+
+    *reader = &reader[len..];
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/aseprite");
+
+    // rgba.aseprite is 32x32 with three frames. The "art" layer has a red
+    // circle, drawn at (4, 8) in frame 0 and (8, 8) in frame 1, with frame 2
+    // linked to frame 0. The "hot" layer has a single red marker pixel per
+    // frame. The "walk" tag plays all three frames ping-pong, and the "pivot"
+    // slice at (2, 3) has its pivot at (6, 20).
+    #[test]
+    fn rgba_document() {
+        let document = super::Document::open(format!("{FIXTURES}/rgba.aseprite")).unwrap();
+
+        assert_eq!(document.size, (32, 32));
+        assert_eq!(document.color_depth, super::ColorDepth::Rgba);
+        assert_eq!(document.frames.len(), 3);
+        assert_eq!(document.layer_index("hot"), Some(1));
+        assert!(matches!(
+            document.frames[2].cels[0].data,
+            super::CelData::Linked(0)
+        ));
+
+        let hot = document.layer_index("hot");
+        let frames = (0..3)
+            .map(|frame| document.render(frame, hot))
+            .collect::<Vec<_>>();
+        let red = image::Rgba([200.0 / 255.0, 50.0 / 255.0, 50.0 / 255.0, 1.0]);
+
+        // The circle's middle, and the pixel left of where it starts.
+        assert_eq!(*frames[0].get_pixel(12, 16), red);
+        assert_eq!(frames[0].get_pixel(4, 16)[3], 0.0);
+        assert_eq!(*frames[1].get_pixel(16, 16), red);
+        assert_eq!(frames[1].get_pixel(8, 16)[3], 0.0);
+        // Linked cels take the position and pixels of the cel they link to.
+        assert_eq!(frames[2], frames[0]);
+
+        let markers = document.render_layer(0, 1);
+        assert_eq!(markers.get_pixel(10, 10)[3], 1.0);
+        assert_eq!(markers.pixels().filter(|pixel| pixel[3] > 0.0).count(), 1);
+
+        // Nothing is indexed, so everything has to be dithered.
+        assert!(!document.uses_bgf_palette());
+        assert_eq!(document.render_indices(0, hot), None);
+
+        let walk = document.tags.iter().find(|tag| tag.name == "walk").unwrap();
+        assert_eq!(walk.direction, super::Direction::PingPong);
+        assert_eq!(document.tag_frames(walk), [0, 1, 2, 1]);
+        assert_eq!(document.pivot(0), Some((8, 23)));
+
+        let mut sheet = crate::conf::Sheet::new("rgba.aseprite".into());
+        sheet.hotspot_layer = Some("hot".to_string());
+        let (bitmaps, groups) = super::slice(&sheet, std::path::Path::new(FIXTURES)).unwrap();
+
+        assert_eq!(bitmaps.len(), 3);
+        assert_eq!(groups, [vec![0, 1, 2, 1], vec![1]]);
+        assert!(
+            bitmaps
+                .iter()
+                .all(|b| b.anchor == Some(crate::conf::Anchor::Pivot((8, 23))))
+        );
+    }
+
+    // indexed.aseprite is 12x8, with the BGF palette and a background layer.
+    // The art covers x 4..10 and y 3..6, and everything else is the document's
+    // transparent index 0.
+    #[test]
+    fn indexed_document() {
+        let mut document = super::Document::open(format!("{FIXTURES}/indexed.aseprite")).unwrap();
+        let (transparent_index, _) = crate::bgf::Palette::new().transparent_color();
+
+        assert_eq!(document.color_depth, super::ColorDepth::Indexed);
+        assert_eq!(document.transparent_index, 0);
+        assert!(document.uses_bgf_palette());
+
+        let indices = document.render_indices(0, None).unwrap();
+        let expected = (0..8)
+            .flat_map(|y| {
+                (0..12).map(move |x| {
+                    if (4..10).contains(&x) && (3..6).contains(&y) {
+                        ((x * 7 + y * 3) % 200 + 10) as u8
+                    } else {
+                        transparent_index as u8
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(indices, expected);
+
+        // The color drawn matches the indices, with the same pixels left out.
+        let image_buffer = document.render(0, None);
+        let palette = crate::bgf::Palette::new();
+        for (pixel, index) in image_buffer.pixels().zip(&indices) {
+            if *index as usize == transparent_index {
+                assert_eq!(pixel[3], 0.0);
+            } else {
+                let color = palette.values()[*index as usize].0;
+                assert_eq!(
+                    pixel.0,
+                    [color[0], color[1], color[2], 255].map(|v| v as f32 / 255.0)
+                );
+            }
+        }
+
+        // Any color off from the BGF palette, even the transparent one, means
+        // the indices can't be used as they are.
+        document.palette[0] = [1, 2, 3, 255];
+        assert!(!document.uses_bgf_palette());
+    }
+}
@@ -0,0 +1,337 @@
+use color_eyre::eyre::{self, Result};
+
+// Packing bitmaps into a single sheet, with a manifest in the JSON hash format
+// TexturePacker and Aseprite write, so other tools can find the frames in it.
+
+// Space left between frames, so filtering in viewers doesn't bleed them into
+// each other.
+const PADDING: u32 = 1;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AtlasMode {
+    // Every bitmap in one sheet.
+    #[default]
+    All,
+    // One sheet per index group.
+    Group,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Size {
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Frame {
+    // Only set when the manifest is written as an array.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    pub frame: Rect,
+    #[serde(default)]
+    pub rotated: bool,
+    #[serde(default)]
+    pub trimmed: bool,
+    // Where the frame sat in the untrimmed image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sprite_source_size: Option<Rect>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_size: Option<Size>,
+    // Not part of the format, but kept so nothing from the BGF is lost.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<(i32, i32)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hotspots: Vec<crate::conf::Hotspot>,
+}
+
+// An animation in an Aseprite manifest, as a range of frames.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct FrameTag {
+    pub name: String,
+    pub from: usize,
+    pub to: usize,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Meta {
+    pub app: String,
+    pub version: String,
+    pub image: String,
+    pub format: String,
+    pub size: Option<Size>,
+    pub scale: String,
+    #[serde(rename = "frameTags", skip_serializing_if = "Vec::is_empty")]
+    pub frame_tags: Vec<FrameTag>,
+}
+
+// Manifests are written as a hash, but both tools can also write the frames as
+// an array.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum Frames {
+    Hash(std::collections::BTreeMap<String, Frame>),
+    Array(Vec<Frame>),
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub frames: Frames,
+    #[serde(default)]
+    pub meta: Meta,
+}
+
+impl Manifest {
+    pub fn read<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Ok(serde_json::from_reader(std::fs::File::open(path)?)?)
+    }
+
+    pub fn frame(&self, name: &str) -> Option<&Frame> {
+        match &self.frames {
+            Frames::Hash(frames) => frames.get(name),
+            Frames::Array(frames) => frames
+                .iter()
+                .find(|frame| frame.filename.as_deref() == Some(name)),
+        }
+    }
+
+    // The frames in order. A hash loses the order they were written in, so the
+    // names are sorted with any numbers in them compared by value, which puts
+    // `walk 2` before `walk 10`.
+    pub fn frames(&self) -> Vec<&Frame> {
+        match &self.frames {
+            Frames::Array(frames) => frames.iter().collect(),
+            Frames::Hash(frames) => {
+                let mut frames = frames.iter().collect::<Vec<_>>();
+                frames.sort_by_key(|(name, _)| natural_key(name));
+                frames.into_iter().map(|(_, frame)| frame).collect()
+            }
+        }
+    }
+}
+
+// Split a name into runs of text and numbers, so numbers sort by value.
+fn natural_key(name: &str) -> Vec<(String, u64)> {
+    let mut key = Vec::new();
+    let mut chars = name.chars().peekable();
+
+    while chars.peek().is_some() {
+        let mut text = String::new();
+        let mut number = String::new();
+
+        while let Some(c) = chars.next_if(|c| !c.is_ascii_digit()) {
+            text.push(c);
+        }
+        while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+            number.push(c);
+        }
+
+        key.push((text, number.parse().unwrap_or_default()));
+    }
+
+    key
+}
+
+// Place frames of the given sizes on shelves, tallest first, keeping the sheet
+// roughly square. Returns the sheet size and where each frame went.
+pub fn pack(sizes: &[(u32, u32)]) -> ((u32, u32), Vec<(u32, u32)>) {
+    let area = sizes
+        .iter()
+        .map(|(w, h)| (*w + PADDING) as u64 * (*h + PADDING) as u64)
+        .sum::<u64>();
+    let widest = sizes.iter().map(|(w, _)| *w).max().unwrap_or_default();
+    let sheet_width = ((area as f64).sqrt().ceil() as u32).max(widest);
+    let mut order = (0..sizes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|i| std::cmp::Reverse(sizes[*i].1));
+
+    let mut positions = vec![(0, 0); sizes.len()];
+    let (mut x, mut y, mut shelf_height) = (0, 0, 0);
+    let mut used_width = 0;
+
+    for index in order {
+        let (w, h) = sizes[index];
+
+        if x > 0 && x + w > sheet_width {
+            (x, y) = (0, y + shelf_height + PADDING);
+            shelf_height = 0;
+        }
+
+        positions[index] = (x, y);
+        used_width = used_width.max(x + w);
+        shelf_height = shelf_height.max(h);
+        x += w + PADDING;
+    }
+
+    ((used_width, y + shelf_height), positions)
+}
+
+// Draw the bitmaps into one sheet, with the rest of it left as the transparent
+// color, and describe where they went. `names` are the manifest keys for each
+// bitmap.
+pub fn build(
+    bitmaps: &[&crate::bgf::Bitmap],
+    names: &[String],
+    image_name: &str,
+) -> Result<(image::RgbImage, Manifest)> {
+    let sizes = bitmaps
+        .iter()
+        .map(|b| (b.size.0 as u32, b.size.1 as u32))
+        .collect::<Vec<_>>();
+    let (sheet_size, positions) = pack(&sizes);
+    let (_, transparent_color) = crate::bgf::Palette::new().transparent_color();
+    let mut sheet =
+        image::RgbImage::from_pixel(sheet_size.0.max(1), sheet_size.1.max(1), transparent_color);
+    let mut frames = std::collections::BTreeMap::new();
+
+    for ((bitmap, name), (x, y)) in bitmaps.iter().zip(names).zip(positions) {
+        image::imageops::replace(&mut sheet, &bitmap.to_image()?, x as i64, y as i64);
+
+        let (w, h) = (bitmap.size.0 as u32, bitmap.size.1 as u32);
+        frames.insert(
+            name.clone(),
+            Frame {
+                filename: None,
+                frame: Rect { x, y, w, h },
+                rotated: false,
+                trimmed: false,
+                sprite_source_size: Some(Rect { x: 0, y: 0, w, h }),
+                source_size: Some(Size { w, h }),
+                offset: Some(bitmap.offset),
+                hotspots: bitmap
+                    .hotspots
+                    .iter()
+                    .map(|h| crate::conf::Hotspot {
+                        number: h.number,
+                        position: crate::conf::Point(h.position.0, h.position.1),
+                    })
+                    .collect(),
+            },
+        );
+    }
+
+    let manifest = Manifest {
+        frames: Frames::Hash(frames),
+        meta: Meta {
+            app: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            image: image_name.to_string(),
+            format: "RGB888".to_string(),
+            size: Some(Size {
+                w: sheet.width(),
+                h: sheet.height(),
+            }),
+            scale: "1".to_string(),
+            frame_tags: Vec::new(),
+        },
+    };
+
+    Ok((sheet, manifest))
+}
+
+// Cut a sheet up into bitmaps, either on a grid or where a manifest says the
+// frames are. Returns the bitmaps along with groups of them, as indices into
+// the returned bitmaps: one per tag in the manifest, or one for the whole sheet
+// if there aren't any.
+pub fn slice(
+    sheet: &crate::conf::Sheet,
+    conf_dir: &std::path::Path,
+) -> Result<(Vec<crate::conf::Bitmap>, Vec<Vec<usize>>)> {
+    let bitmap = |rect: Rect,
+                  offset: (i32, i32),
+                  origin: Option<(i32, i32)>,
+                  hotspots: Vec<crate::conf::Hotspot>| crate::conf::Bitmap {
+        size: (rect.w as i32, rect.h as i32),
+        offset,
+        hotspots,
+        compression: sheet.compression,
+        path: sheet.path.clone(),
+        dither: sheet.dither.clone(),
+        dither_mask: None,
+        anchor: sheet.anchor,
+        hotspot_image: None,
+        hotspot_markers: Vec::new(),
+        rect: Some(rect),
+        origin,
+        frame: None,
+        hotspot_layer: None,
+    };
+
+    match (&sheet.grid, &sheet.manifest) {
+        (Some(grid), None) => {
+            let (width, height) = image::image_dimensions(conf_dir.join(&sheet.path))?;
+            let (frame_width, frame_height) = grid.frame_size;
+
+            if frame_width == 0 || frame_height == 0 {
+                return Err(eyre::eyre!("Sheet frames can't be empty."));
+            }
+
+            let fit = |size: u32, frame: u32| {
+                (size.saturating_sub(grid.margin) + grid.spacing) / (frame + grid.spacing)
+            };
+            let (columns, rows) = (fit(width, frame_width), fit(height, frame_height));
+            let count = grid.count.unwrap_or(columns * rows).min(columns * rows);
+            let bitmaps = (0..count)
+                .map(|i| {
+                    let rect = Rect {
+                        x: grid.margin + (i % columns) * (frame_width + grid.spacing),
+                        y: grid.margin + (i / columns) * (frame_height + grid.spacing),
+                        w: frame_width,
+                        h: frame_height,
+                    };
+                    bitmap(rect, sheet.offset, None, Vec::new())
+                })
+                .collect::<Vec<_>>();
+            let groups = vec![(0..bitmaps.len()).collect()];
+
+            Ok((bitmaps, groups))
+        }
+        (None, Some(manifest_path)) => {
+            let manifest = Manifest::read(conf_dir.join(manifest_path))?;
+            let frames = manifest.frames();
+            let bitmaps = frames
+                .iter()
+                .map(|frame| {
+                    if frame.rotated {
+                        return Err(eyre::eyre!("Rotated sheet frames aren't supported."));
+                    }
+
+                    // Trimmed frames move over by however much was cut off, so
+                    // they still line up with the others. Anchors are found
+                    // from the same place.
+                    let origin = frame.sprite_source_size.map(|r| (r.x as i32, r.y as i32));
+                    let offset = frame.offset.unwrap_or_else(|| {
+                        let trim = origin.unwrap_or_default();
+                        (sheet.offset.0 + trim.0, sheet.offset.1 + trim.1)
+                    });
+
+                    Ok(bitmap(frame.frame, offset, origin, frame.hotspots.clone()))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let groups = if manifest.meta.frame_tags.is_empty() {
+                vec![(0..bitmaps.len()).collect()]
+            } else {
+                manifest
+                    .meta
+                    .frame_tags
+                    .iter()
+                    .map(|tag| (tag.from..=tag.to.min(bitmaps.len().saturating_sub(1))).collect())
+                    .collect()
+            };
+
+            Ok((bitmaps, groups))
+        }
+        _ => Err(eyre::eyre!(
+            "Sheet {} needs either a grid or a manifest.",
+            sheet.path.display()
+        )),
+    }
+}
@@ -1,10 +1,9 @@
 use std::{io::prelude::*, str::FromStr};
 
 use color_eyre::eyre::{self, Ok, Result};
-use rayon::prelude::*;
 
 const MAGIC_NUMBER: &[u8] = b"BGF\x11";
-const CURRENT_BGF_VERSION: i32 = 10;
+pub const CURRENT_BGF_VERSION: i32 = 10;
 const MAX_BITMAP_NAME_LEN: usize = 32;
 const PALETTE: &[[u8; 3]] = &[
     [0, 0, 0],
@@ -306,11 +305,59 @@ impl Hotspot {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone)]
 pub struct BitmapImageOptions {
     pub compression: crate::conf::BitmapDataCompression,
     pub transparency_clip: f32,
+    pub alpha: crate::dither::alpha::AlphaMode,
+    pub matte: Option<[u8; 3]>,
     pub dither: crate::dither::DitherOptions,
+    pub ditherer: Option<String>,
+    pub auto_metric: crate::dither::quality::Metric,
+    pub blue_noise_size: usize,
+    pub blue_noise_offset: (usize, usize),
+    pub serpentine: bool,
+    pub border: crate::dither::BorderPolicy,
+    pub mask_diffusion: bool,
+    pub protect_exact: bool,
+    pub dither_mask: Option<Vec<bool>>,
+    pub diffusion_kernel: Option<crate::conf::DiffusionKernel>,
+    pub riemersma_history: usize,
+    pub dither_strength: f32,
+    pub dither_seed: u64,
+    pub linear_light: bool,
+    pub canvas_origin: (usize, usize),
+    pub canvas_size: Option<(u32, u32)>,
+    pub locked_indices: Option<Vec<Option<u8>>>,
+}
+
+impl Default for BitmapImageOptions {
+    fn default() -> Self {
+        Self {
+            compression: Default::default(),
+            transparency_clip: 0.0,
+            alpha: Default::default(),
+            matte: None,
+            dither: Default::default(),
+            ditherer: None,
+            auto_metric: Default::default(),
+            blue_noise_size: 64,
+            blue_noise_offset: (0, 0),
+            serpentine: false,
+            border: Default::default(),
+            mask_diffusion: false,
+            protect_exact: true,
+            dither_mask: None,
+            diffusion_kernel: None,
+            riemersma_history: 16,
+            dither_strength: 1.0,
+            dither_seed: 0,
+            linear_light: false,
+            canvas_origin: (0, 0),
+            canvas_size: None,
+            locked_indices: None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -432,9 +479,9 @@ impl Bitmap {
         Ok(())
     }
 
-    pub fn save_image<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+    pub fn indices(&self) -> Result<std::borrow::Cow<'_, [u8]>> {
         let data = match &self.data {
-            BitmapData::Uncompressed(items) => std::borrow::Cow::Borrowed(items),
+            BitmapData::Uncompressed(items) => std::borrow::Cow::Borrowed(&items[..]),
             BitmapData::ZlibCompressed(items) => {
                 let mut decoder = flate2::read::ZlibDecoder::new(&items[..]);
                 let mut data = Vec::new();
@@ -443,6 +490,76 @@ impl Bitmap {
             }
         };
 
+        Ok(data)
+    }
+
+    // Crop away fully transparent rows and columns around the edges, moving
+    // the offset and hotspots so the sprite still lands in the same place.
+    // Returns how far the top left corner moved.
+    pub fn trim(&mut self) -> Result<(i32, i32)> {
+        let (transparent_index, _) = Palette::new().transparent_color();
+        let width = self.size.0 as usize;
+        let height = self.size.1 as usize;
+        let (crop, size, buf) = {
+            let indices = self.indices()?;
+            let opaque = |x: usize, y: usize| indices[y * width + x] as usize != transparent_index;
+            let rows = (0..height)
+                .filter(|y| (0..width).any(|x| opaque(x, *y)))
+                .collect::<Vec<_>>();
+            let columns = (0..width)
+                .filter(|x| (0..height).any(|y| opaque(*x, y)))
+                .collect::<Vec<_>>();
+
+            // Leave fully transparent bitmaps alone.
+            let (Some(top), Some(bottom), Some(left), Some(right)) =
+                (rows.first(), rows.last(), columns.first(), columns.last())
+            else {
+                return Ok((0, 0));
+            };
+
+            if (*left, *top, *right, *bottom) == (0, 0, width - 1, height - 1) {
+                return Ok((0, 0));
+            }
+
+            let buf = (*top..=*bottom)
+                .flat_map(|y| {
+                    indices[y * width + left..=y * width + right]
+                        .iter()
+                        .copied()
+                })
+                .collect::<Vec<_>>();
+
+            (
+                (*left as i32, *top as i32),
+                ((right - left + 1) as u32, (bottom - top + 1) as u32),
+                buf,
+            )
+        };
+        let compression = match self.data {
+            BitmapData::Uncompressed(_) => crate::conf::BitmapDataCompression::Uncompressed,
+            BitmapData::ZlibCompressed(_) => crate::conf::BitmapDataCompression::ZlibCompressed,
+        };
+        let trimmed = Self::from_indices(size, buf, compression)?;
+
+        self.size = trimmed.size;
+        self.data = trimmed.data;
+        self.offset = (self.offset.0 + crop.0, self.offset.1 + crop.1);
+
+        for hotspot in &mut self.hotspots {
+            hotspot.position = Point(hotspot.position.0 - crop.0, hotspot.position.1 - crop.1);
+        }
+
+        Ok(crop)
+    }
+
+    pub fn save_image<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        self.to_image()?.save(path)?;
+
+        Ok(())
+    }
+
+    pub fn to_image(&self) -> Result<image::RgbImage> {
+        let data = self.indices()?;
         let mut img = image::RgbImage::new(self.size.0 as u32, self.size.1 as u32);
 
         for (index, palette_index) in data.iter().enumerate() {
@@ -453,52 +570,95 @@ impl Bitmap {
             img.put_pixel(x, y, image::Rgb(pixel));
         }
 
-        img.save(path)?;
-
-        Ok(())
+        Ok(img)
     }
 
     pub fn from_image<P: AsRef<std::path::Path>>(
         path: P,
         options: &BitmapImageOptions,
     ) -> Result<Self> {
+        let image_buffer = Self::open_image(path)?;
+        let buf = Self::quantize(&image_buffer, options)?;
+
+        Self::from_indices(
+            (image_buffer.width(), image_buffer.height()),
+            buf,
+            options.compression,
+        )
+    }
+
+    pub fn open_image<P: AsRef<std::path::Path>>(path: P) -> Result<image::Rgba32FImage> {
         let img = image::ImageReader::open(path)?
             .with_guessed_format()?
             .decode()?;
-        let width = img.width();
-        let height = img.height();
-        let palette = Palette::new();
 
-        let image_buffer = img.into_rgba32f();
+        Ok(img.into_rgba32f())
+    }
 
-        let generator = match options.dither {
-            crate::dither::DitherOptions::None => crate::dither::DitherGenerator::new_none(),
-            crate::dither::DitherOptions::R2 => crate::dither::DitherGenerator::new_r2(0.0),
-            crate::dither::DitherOptions::Pcg => {
-                crate::dither::DitherGenerator::new_pcg(0, (width * height) as usize)
-            }
-            crate::dither::DitherOptions::FloydSteinberg => {
-                crate::dither::DitherGenerator::new_floyd_steinberg()
-            }
-            crate::dither::DitherOptions::JavisJudiceNinke => {
-                crate::dither::DitherGenerator::new_javis_judice_ninke()
-            }
-            crate::dither::DitherOptions::Stucki => crate::dither::DitherGenerator::new_stucki(),
-            crate::dither::DitherOptions::Atkinson => {
-                crate::dither::DitherGenerator::new_atkinson()
-            }
-            crate::dither::DitherOptions::Burkes => crate::dither::DitherGenerator::new_burkes(),
-            crate::dither::DitherOptions::Sierra => crate::dither::DitherGenerator::new_sierra(),
-            crate::dither::DitherOptions::TwoRowSierra => {
-                crate::dither::DitherGenerator::new_two_row_sierra()
-            }
-            crate::dither::DitherOptions::SierraLite => {
-                crate::dither::DitherGenerator::new_sierra_lite()
+    pub fn quantize(
+        image_buffer: &image::Rgba32FImage,
+        options: &BitmapImageOptions,
+    ) -> Result<Vec<u8>> {
+        if options.dither == crate::dither::DitherOptions::Auto && options.ditherer.is_none() {
+            let (dither, _) =
+                crate::dither::quality::best_dither(image_buffer, options, options.auto_metric)?;
+
+            return Self::quantize(
+                image_buffer,
+                &BitmapImageOptions {
+                    dither,
+                    ..options.clone()
+                },
+            );
+        }
+
+        let palette = Palette::new();
+        let size = (image_buffer.width(), image_buffer.height());
+        let protected_options;
+        let options = match crate::dither::protected_indices(image_buffer, options, &palette) {
+            Some(locked_indices) => {
+                protected_options = BitmapImageOptions {
+                    locked_indices: Some(locked_indices),
+                    ..options.clone()
+                };
+                &protected_options
             }
+            None => options,
+        };
+        let ditherer: Box<dyn crate::dither::Ditherer> = match &options.ditherer {
+            Some(name) => crate::dither::registry::create(name, options, size)?,
+            None => Box::new(crate::dither::DitherGenerator::from_options(options, size)?),
         };
+        let mut indices = ditherer.dither(image_buffer, options, &palette);
+
+        if indices.len() != (size.0 * size.1) as usize {
+            return Err(eyre::eyre!(
+                "Dither returned {} indices for a {}x{} image.",
+                indices.len(),
+                size.0,
+                size.1
+            ));
+        }
+
+        // Dithers from other crates don't have to know about locked pixels.
+        if let Some(locked_indices) = &options.locked_indices {
+            indices
+                .iter_mut()
+                .zip(locked_indices)
+                .filter_map(|(index, locked)| locked.map(|locked| (index, locked)))
+                .for_each(|(index, locked)| *index = locked);
+        }
+
+        Ok(indices)
+    }
 
-        let buf = generator.dither(&image_buffer, options, &palette);
-        let data = match options.compression {
+    pub fn from_indices(
+        size: (u32, u32),
+        buf: Vec<u8>,
+        compression: crate::conf::BitmapDataCompression,
+    ) -> Result<Self> {
+        let (width, height) = size;
+        let data = match compression {
             crate::conf::BitmapDataCompression::Uncompressed => BitmapData::Uncompressed(buf),
             crate::conf::BitmapDataCompression::ZlibCompressed => {
                 let mut encoder =
@@ -685,10 +845,75 @@ impl Bgf {
 
         Ok(())
     }
+
+    // Store bitmaps with the same pixels, offset and hotspots only once, and
+    // point the index groups at the copy that is kept.
+    pub fn dedupe(&mut self) -> Result<Dedupe> {
+        let mut kept = std::collections::HashMap::new();
+        let mut remap = Vec::with_capacity(self.bitmaps.len());
+        let mut bitmaps = Vec::with_capacity(self.bitmaps.len());
+        let mut bytes_saved = 0;
+
+        for bitmap in std::mem::take(&mut self.bitmaps) {
+            let mut hotspots = bitmap
+                .hotspots
+                .iter()
+                .map(|h| (h.number, h.position.0, h.position.1))
+                .collect::<Vec<_>>();
+            hotspots.sort();
+            let key = (
+                bitmap.size,
+                bitmap.offset,
+                hotspots,
+                bitmap.indices()?.into_owned(),
+            );
+
+            match kept.get(&key) {
+                Some(index) => {
+                    let mut bytes = Vec::new();
+                    bitmap.write(&mut bytes)?;
+                    bytes_saved += bytes.len();
+                    remap.push(*index);
+                }
+                None => {
+                    kept.insert(key, bitmaps.len());
+                    remap.push(bitmaps.len());
+                    bitmaps.push(bitmap);
+                }
+            }
+        }
+
+        for group in &mut self.index_groups {
+            for index in &mut group.indices {
+                // Leave anything that doesn't point at a bitmap as it is.
+                if let Some(new_index) = usize::try_from(*index).ok().and_then(|i| remap.get(i)) {
+                    *index = *new_index as i32;
+                }
+            }
+        }
+
+        let removed = remap.len() - bitmaps.len();
+        self.bitmaps = bitmaps;
+
+        Ok(Dedupe {
+            remap,
+            removed,
+            bytes_saved,
+        })
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Dedupe {
+    // Where each of the original bitmaps ended up.
+    pub remap: Vec<usize>,
+    pub removed: usize,
+    pub bytes_saved: usize,
 }
 
 pub struct Palette {
     values: &'static [image::Rgb<u8>],
+    linear_values: &'static [image::Rgb<f32>],
 }
 
 impl Default for Palette {
@@ -701,9 +926,17 @@ impl Palette {
     pub fn new() -> Self {
         static CACHED_PALETTE: std::sync::LazyLock<Vec<image::Rgb<u8>>> =
             std::sync::LazyLock::new(|| PALETTE.iter().map(|v| image::Rgb(*v)).collect());
+        static CACHED_LINEAR_PALETTE: std::sync::LazyLock<Vec<image::Rgb<f32>>> =
+            std::sync::LazyLock::new(|| {
+                PALETTE
+                    .iter()
+                    .map(|v| image::Rgb(v.map(|c| crate::dither::srgb_to_linear(c as f32 / 255.0))))
+                    .collect()
+            });
 
         Self {
             values: CACHED_PALETTE.as_ref(),
+            linear_values: CACHED_LINEAR_PALETTE.as_ref(),
         }
     }
 
@@ -715,9 +948,31 @@ impl Palette {
         self.values
     }
 
+    pub fn linear_values(&self) -> &[image::Rgb<f32>] {
+        self.linear_values
+    }
+
+    pub fn find_closest_linear(&self, color: &image::Rgb<f32>) -> (usize, &image::Rgb<f32>) {
+        self.linear_values
+            .iter()
+            .enumerate()
+            .filter(|i| i.0 != self.transparent_color().0) // Skip the transparent color
+            .min_by(|(_, a), (_, b)| {
+                let aa = (a[0] - color[0]).powi(2)
+                    + (a[1] - color[1]).powi(2)
+                    + (a[2] - color[2]).powi(2);
+                let bb = (b[0] - color[0]).powi(2)
+                    + (b[1] - color[1]).powi(2)
+                    + (b[2] - color[2]).powi(2);
+
+                aa.partial_cmp(&bb).unwrap()
+            })
+            .unwrap()
+    }
+
     pub fn find_closest(&self, color: &image::Rgb<u8>) -> (usize, &image::Rgb<u8>) {
         self.values
-            .par_iter()
+            .iter()
             .enumerate()
             .filter(|i| i.0 != self.transparent_color().0) // Skip the transparent color
             .min_by(|(_, a), (_, b)| {
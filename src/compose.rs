@@ -0,0 +1,265 @@
+use color_eyre::eyre::{self, Result};
+
+// Drawing other BGFs attached to a base BGF's hotspots, the way heads, weapons
+// and shields are put on a body. An overlay is placed with its own offset from
+// the hotspot it's bound to, and goes beneath the base when the base frame's
+// hotspot number is negative.
+
+pub struct Overlay<'a> {
+    pub bgf: &'a crate::bgf::Bgf,
+    // Matched against the base hotspots ignoring the sign.
+    pub hotspot: i8,
+    pub group: usize,
+}
+
+// Render each frame of a base group with its overlays, all on one canvas. The
+// overlay's frames are played alongside the base's, looping if there are fewer.
+pub fn compose(
+    base: &crate::bgf::Bgf,
+    group: &crate::bgf::Group,
+    overlays: &[Overlay],
+    options: &crate::animate::AnimationOptions,
+) -> Result<Vec<image::RgbaImage>> {
+    let base_frames = group_bitmaps(base, group)?;
+    let overlay_frames = overlays
+        .iter()
+        .map(|overlay| {
+            let group = overlay
+                .bgf
+                .index_groups
+                .get(overlay.group)
+                .ok_or_else(|| eyre::eyre!("Overlay has no group {}.", overlay.group))?;
+
+            group_bitmaps(overlay.bgf, group)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let frames = base_frames
+        .iter()
+        .enumerate()
+        .map(|(frame, base_bitmap)| {
+            let mut beneath = Vec::new();
+            let mut above = Vec::new();
+
+            for (overlay, bitmaps) in overlays.iter().zip(&overlay_frames) {
+                let Some(hotspot) = base_bitmap
+                    .hotspots
+                    .iter()
+                    .find(|h| h.number.unsigned_abs() == overlay.hotspot.unsigned_abs())
+                else {
+                    continue;
+                };
+                let bitmap = bitmaps[frame % bitmaps.len()];
+                let position = (
+                    base_bitmap.offset.0 + hotspot.position.0 + bitmap.offset.0,
+                    base_bitmap.offset.1 + hotspot.position.1 + bitmap.offset.1,
+                );
+
+                if hotspot.number < 0 {
+                    beneath.push((bitmap, position));
+                } else {
+                    above.push((bitmap, position));
+                }
+            }
+
+            beneath
+                .into_iter()
+                .chain([(*base_bitmap, base_bitmap.offset)])
+                .chain(above)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    draw(&frames, options)
+}
+
+fn group_bitmaps<'a>(
+    bgf: &'a crate::bgf::Bgf,
+    group: &crate::bgf::Group,
+) -> Result<Vec<&'a crate::bgf::Bitmap>> {
+    let bitmaps = group
+        .indices
+        .iter()
+        .map(|index| {
+            usize::try_from(*index)
+                .ok()
+                .and_then(|i| bgf.bitmaps.get(i))
+                .ok_or_else(|| eyre::eyre!("Group points at missing bitmap {index}."))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if bitmaps.is_empty() {
+        return Err(eyre::eyre!("Group has no frames."));
+    }
+
+    Ok(bitmaps)
+}
+
+// Draw each frame's bitmaps in order at their positions, on a canvas big enough
+// for every frame.
+fn draw(
+    frames: &[Vec<(&crate::bgf::Bitmap, (i32, i32))>],
+    options: &crate::animate::AnimationOptions,
+) -> Result<Vec<image::RgbaImage>> {
+    let placed = frames.iter().flatten().collect::<Vec<_>>();
+    let canvas_min = placed
+        .iter()
+        .fold((i32::MAX, i32::MAX), |acc, (_, position)| {
+            (acc.0.min(position.0), acc.1.min(position.1))
+        });
+    let canvas_max = placed
+        .iter()
+        .fold((i32::MIN, i32::MIN), |acc, (bitmap, position)| {
+            (
+                acc.0.max(position.0 + bitmap.size.0),
+                acc.1.max(position.1 + bitmap.size.1),
+            )
+        });
+    let canvas_size = (
+        ((canvas_max.0 - canvas_min.0) as u32).max(1),
+        ((canvas_max.1 - canvas_min.1) as u32).max(1),
+    );
+    let palette = crate::bgf::Palette::new();
+    let (transparent_index, _) = palette.transparent_color();
+    let empty = match options.background {
+        Some([r, g, b]) => image::Rgba([r, g, b, 255]),
+        None => image::Rgba([0, 0, 0, 0]),
+    };
+    let scale = options.scale.max(1);
+    let scaled_size = canvas_size
+        .0
+        .checked_mul(scale)
+        .zip(canvas_size.1.checked_mul(scale))
+        .ok_or_else(|| eyre::eyre!("Scaling by {scale} makes the frames too big."))?;
+
+    frames
+        .iter()
+        .map(|bitmaps| {
+            let mut canvas = image::RgbaImage::from_pixel(canvas_size.0, canvas_size.1, empty);
+
+            for (bitmap, position) in bitmaps {
+                let (left, top) = (
+                    (position.0 - canvas_min.0) as u32,
+                    (position.1 - canvas_min.1) as u32,
+                );
+                let width = bitmap.size.0 as usize;
+
+                for (i, index) in bitmap.indices()?.iter().enumerate() {
+                    if *index as usize == transparent_index {
+                        continue;
+                    }
+
+                    let [r, g, b] = palette.values()[*index as usize].0;
+                    canvas.put_pixel(
+                        left + (i % width) as u32,
+                        top + (i / width) as u32,
+                        image::Rgba([r, g, b, 255]),
+                    );
+                }
+            }
+
+            Ok(if scale == 1 {
+                canvas
+            } else {
+                image::imageops::resize(
+                    &canvas,
+                    scaled_size.0,
+                    scaled_size.1,
+                    image::imageops::FilterType::Nearest,
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bgf(
+        size: (u32, u32),
+        index: u8,
+        offset: (i32, i32),
+        hotspots: Vec<crate::bgf::Hotspot>,
+    ) -> crate::bgf::Bgf {
+        let mut bitmap = crate::bgf::Bitmap::from_indices(
+            size,
+            vec![index; (size.0 * size.1) as usize],
+            crate::conf::BitmapDataCompression::Uncompressed,
+        )
+        .unwrap();
+        bitmap.offset = offset;
+        bitmap.hotspots = hotspots;
+
+        crate::bgf::Bgf {
+            version: 10,
+            name: String::new(),
+            bitmaps: vec![bitmap],
+            index_groups: vec![crate::bgf::Group { indices: vec![0] }],
+            shrink_factor: 1,
+        }
+    }
+
+    #[test]
+    fn overlays_sit_on_hotspots() {
+        let base = bgf(
+            (4, 4),
+            10,
+            (-1, -2),
+            vec![
+                crate::bgf::Hotspot {
+                    number: 1,
+                    position: crate::bgf::Point(1, 1),
+                },
+                crate::bgf::Hotspot {
+                    number: -2,
+                    position: crate::bgf::Point(2, 2),
+                },
+            ],
+        );
+        let above = bgf((2, 2), 100, (1, 0), Vec::new());
+        let beneath = bgf((3, 3), 200, (0, 0), Vec::new());
+        let frames = compose(
+            &base,
+            &base.index_groups[0],
+            &[
+                Overlay {
+                    bgf: &above,
+                    hotspot: 1,
+                    group: 0,
+                },
+                Overlay {
+                    bgf: &beneath,
+                    hotspot: 2,
+                    group: 0,
+                },
+            ],
+            &crate::animate::AnimationOptions {
+                delay: 100,
+                scale: 1,
+                background: None,
+            },
+        )
+        .unwrap();
+        let palette = crate::bgf::Palette::new();
+        let color = |index: usize| {
+            let [r, g, b] = palette.values()[index].0;
+
+            image::Rgba([r, g, b, 255])
+        };
+        let frame = &frames[0];
+
+        // The canvas starts at the base's offset, so the positive hotspot puts
+        // the first overlay at (1, 1) + (1, 0) on it, over the base, and the
+        // negative one puts the second at (2, 2), under it.
+        assert_eq!(frame.dimensions(), (5, 5));
+        assert_eq!(*frame.get_pixel(0, 0), color(10));
+        assert_eq!(*frame.get_pixel(1, 1), color(10));
+        assert_eq!(*frame.get_pixel(2, 1), color(100));
+        assert_eq!(*frame.get_pixel(3, 2), color(100));
+        assert_eq!(*frame.get_pixel(2, 3), color(10));
+        assert_eq!(*frame.get_pixel(3, 3), color(10));
+        assert_eq!(*frame.get_pixel(4, 2), color(200));
+        assert_eq!(*frame.get_pixel(4, 4), color(200));
+        assert_eq!(*frame.get_pixel(4, 0), image::Rgba([0, 0, 0, 0]));
+    }
+}
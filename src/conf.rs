@@ -1,4 +1,6 @@
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+use color_eyre::eyre::{self, Result};
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub struct Point(pub i32, pub i32);
 
 impl From<crate::bgf::Point> for Point {
@@ -7,7 +9,7 @@ impl From<crate::bgf::Point> for Point {
     }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Hotspot {
     pub number: i8,
     pub position: Point,
@@ -22,7 +24,7 @@ impl From<crate::bgf::Hotspot> for Hotspot {
     }
 }
 
-#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum BitmapDataCompression {
     #[default]
     #[serde(rename = "none")]
@@ -31,6 +33,181 @@ pub enum BitmapDataCompression {
     ZlibCompressed,
 }
 
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DitherSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<crate::dither::DitherOptions>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ditherer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_metric: Option<crate::dither::quality::Metric>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strength: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transparency: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serpentine: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub border: Option<crate::dither::BorderPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mask_diffusion: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protect_exact: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blue_noise_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blue_noise_offset: Option<(usize, usize)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub riemersma_history: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linear_light: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alpha: Option<crate::dither::alpha::AlphaMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matte: Option<[u8; 3]>,
+}
+
+impl DitherSettings {
+    // Fill in anything that isn't set with the values from `fallback`.
+    pub fn or(&self, fallback: &Self) -> Self {
+        Self {
+            // Picking a ditherer replaces an algorithm picked further down, and
+            // the other way around.
+            algorithm: match self.ditherer {
+                Some(_) => self.algorithm,
+                None => self.algorithm.or(fallback.algorithm),
+            },
+            ditherer: match self.algorithm {
+                Some(_) => self.ditherer.clone(),
+                None => self.ditherer.clone().or_else(|| fallback.ditherer.clone()),
+            },
+            auto_metric: self.auto_metric.or(fallback.auto_metric),
+            strength: self.strength.or(fallback.strength),
+            seed: self.seed.or(fallback.seed),
+            transparency: self.transparency.or(fallback.transparency),
+            serpentine: self.serpentine.or(fallback.serpentine),
+            border: self.border.or(fallback.border),
+            mask_diffusion: self.mask_diffusion.or(fallback.mask_diffusion),
+            protect_exact: self.protect_exact.or(fallback.protect_exact),
+            blue_noise_size: self.blue_noise_size.or(fallback.blue_noise_size),
+            blue_noise_offset: self.blue_noise_offset.or(fallback.blue_noise_offset),
+            riemersma_history: self.riemersma_history.or(fallback.riemersma_history),
+            linear_light: self.linear_light.or(fallback.linear_light),
+            alpha: self.alpha.or(fallback.alpha),
+            matte: self.matte.or(fallback.matte),
+        }
+    }
+
+    pub fn apply(&self, options: &mut crate::bgf::BitmapImageOptions) -> Result<()> {
+        if let (Some(_), Some(ditherer)) = (self.algorithm, &self.ditherer) {
+            return Err(eyre::eyre!(
+                "Dither settings pick both an algorithm and the ditherer \"{ditherer}\"."
+            ));
+        }
+
+        if let Some(algorithm) = self.algorithm {
+            options.dither = algorithm;
+        }
+
+        if let Some(ditherer) = &self.ditherer {
+            options.ditherer = Some(ditherer.clone());
+        }
+
+        if let Some(auto_metric) = self.auto_metric {
+            options.auto_metric = auto_metric;
+        }
+
+        if let Some(strength) = self.strength {
+            options.dither_strength = strength;
+        }
+
+        if let Some(seed) = self.seed {
+            options.dither_seed = seed;
+        }
+
+        if let Some(transparency) = self.transparency {
+            options.transparency_clip = transparency;
+        }
+
+        if let Some(serpentine) = self.serpentine {
+            options.serpentine = serpentine;
+        }
+
+        if let Some(border) = self.border {
+            options.border = border;
+        }
+
+        if let Some(mask_diffusion) = self.mask_diffusion {
+            options.mask_diffusion = mask_diffusion;
+        }
+
+        if let Some(protect_exact) = self.protect_exact {
+            options.protect_exact = protect_exact;
+        }
+
+        if let Some(blue_noise_size) = self.blue_noise_size {
+            if !(crate::dither::MIN_BLUE_NOISE_SIZE..=crate::dither::MAX_BLUE_NOISE_SIZE)
+                .contains(&blue_noise_size)
+            {
+                return Err(eyre::eyre!(
+                    "Blue noise size must be between {} and {}.",
+                    crate::dither::MIN_BLUE_NOISE_SIZE,
+                    crate::dither::MAX_BLUE_NOISE_SIZE
+                ));
+            }
+
+            options.blue_noise_size = blue_noise_size;
+        }
+
+        if let Some(blue_noise_offset) = self.blue_noise_offset {
+            options.blue_noise_offset = blue_noise_offset;
+        }
+
+        if let Some(riemersma_history) = self.riemersma_history {
+            options.riemersma_history = riemersma_history;
+        }
+
+        if let Some(linear_light) = self.linear_light {
+            options.linear_light = linear_light;
+        }
+
+        if let Some(alpha) = self.alpha {
+            options.alpha = alpha;
+        }
+
+        if let Some(matte) = self.matte {
+            options.matte = Some(matte);
+        }
+
+        Ok(())
+    }
+}
+
+// A hotspot found from pixels of this exact color, either in the bitmap's
+// image or in its hotspot image.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct HotspotMarker {
+    pub number: i8,
+    pub color: [u8; 3],
+}
+
+// Where a bitmap's offset is worked out from, instead of setting it by hand.
+// The anchor ends up at the object's position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Anchor {
+    // Middle of the bottom edge of the opaque area.
+    BottomCenter,
+    // Middle of the lowest opaque pixels.
+    Feet,
+    // A point in source image coordinates.
+    Pivot((i32, i32)),
+    // Pixels of this exact color, which are removed from the image.
+    Marker([u8; 3]),
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Bitmap {
     pub size: (i32, i32),
@@ -38,6 +215,34 @@ pub struct Bitmap {
     pub hotspots: Vec<Hotspot>,
     pub compression: BitmapDataCompression,
     pub path: std::path::PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dither: Option<DitherSettings>,
+    // Image the same size as the bitmap, where white marks the pixels that
+    // can be dithered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dither_mask: Option<std::path::PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anchor: Option<Anchor>,
+    // Image the same size as the bitmap with the hotspot markers drawn on it,
+    // so they don't have to be in the art itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hotspot_image: Option<std::path::PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hotspot_markers: Vec<HotspotMarker>,
+    // Part of the image to use, for bitmaps that come from a sheet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rect: Option<crate::atlas::Rect>,
+    // Where the bitmap's top left sits in the frame it was cropped out of.
+    // Anchors are found in frame space, so cropped frames still line up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<(i32, i32)>,
+    // Frame to use from an Aseprite document or an animated image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame: Option<usize>,
+    // Aseprite layer with the hotspot markers drawn on it, which is left out
+    // of the bitmap.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hotspot_layer: Option<String>,
 }
 
 impl From<crate::bgf::Bitmap> for Bitmap {
@@ -53,10 +258,58 @@ impl From<crate::bgf::Bitmap> for Bitmap {
             hotspots: value.hotspots.into_iter().map(|h| h.into()).collect(),
             compression,
             path: std::path::PathBuf::new(),
+            dither: None,
+            dither_mask: None,
+            anchor: None,
+            hotspot_image: None,
+            hotspot_markers: Vec::new(),
+            rect: None,
+            origin: None,
+            frame: None,
+            hotspot_layer: None,
         }
     }
 }
 
+// Frames laid out on a grid, going left to right then top to bottom.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Grid {
+    pub frame_size: (u32, u32),
+    // Space around the outside of the sheet.
+    #[serde(default)]
+    pub margin: u32,
+    // Space between frames.
+    #[serde(default)]
+    pub spacing: u32,
+    // How many frames there are, if the last row isn't full.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u32>,
+}
+
+// A single image holding many bitmaps, cut up by either a grid or a
+// TexturePacker or Aseprite JSON manifest. Its bitmaps go after the ones listed
+// in the conf, with an index group for the sheet (or for each tag in the
+// manifest). Aseprite documents and animated GIF, PNG and WebP images need
+// neither, as each frame is a bitmap.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Sheet {
+    pub path: std::path::PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grid: Option<Grid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<std::path::PathBuf>,
+    #[serde(default)]
+    pub offset: (i32, i32),
+    #[serde(default)]
+    pub compression: BitmapDataCompression,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dither: Option<DitherSettings>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anchor: Option<Anchor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hotspot_layer: Option<String>,
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Group {
     pub indices: Vec<i32>,
@@ -70,6 +323,75 @@ impl From<crate::bgf::Group> for Group {
     }
 }
 
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DiffusionKernelEntry {
+    pub weight: f32,
+    pub offset: (isize, isize),
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DiffusionKernel {
+    pub divisor: f32,
+    pub weights: Vec<DiffusionKernelEntry>,
+}
+
+impl DiffusionKernel {
+    pub fn read<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let kernel: Self = serde_json::from_reader(std::fs::File::open(path)?)?;
+        kernel.validate()?;
+
+        Ok(kernel)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !self.divisor.is_finite() || self.divisor <= 0.0 {
+            return Err(eyre::eyre!("Diffusion kernel divisor must be positive."));
+        }
+
+        if self.weights.is_empty() {
+            return Err(eyre::eyre!("Diffusion kernel has no weights."));
+        }
+
+        for entry in &self.weights {
+            if !entry.weight.is_finite() || entry.weight < 0.0 {
+                return Err(eyre::eyre!(
+                    "Diffusion kernel weights must not be negative."
+                ));
+            }
+
+            // Error can only be pushed to pixels that haven't been visited
+            // yet, which is anything to the right on the same row, or on a
+            // later row.
+            let (x, y) = entry.offset;
+
+            if y < 0 || (y == 0 && x <= 0) {
+                return Err(eyre::eyre!(
+                    "Diffusion kernel offset ({x}, {y}) points at an already visited pixel."
+                ));
+            }
+        }
+
+        // Less than 1.0 loses some error on purpose (like Atkinson), more
+        // than 1.0 amplifies error and will blow out the image.
+        let total = self.weights.iter().map(|e| e.weight).sum::<f32>() / self.divisor;
+
+        if total <= 0.0 || total > 1.0 + 1.0e-4 {
+            return Err(eyre::eyre!(
+                "Diffusion kernel weights sum to {total} of the error, expected more than 0.0 and at most 1.0."
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn diffusion(&self) -> Vec<(f32, (isize, isize))> {
+        self.weights
+            .iter()
+            .map(|e| (e.weight / self.divisor, e.offset))
+            .collect()
+    }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Bgf {
     pub version: i32,
@@ -77,6 +399,89 @@ pub struct Bgf {
     pub bitmaps: Vec<Bitmap>,
     pub index_groups: Vec<Group>,
     pub shrink_factor: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diffusion_kernel: Option<DiffusionKernel>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dither: Option<DitherSettings>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub temporal: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub trim: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dedupe: bool,
+    // Anchor for bitmaps that don't set their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anchor: Option<Anchor>,
+    // Hotspot markers for every bitmap, on top of the bitmap's own.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hotspot_markers: Vec<HotspotMarker>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sheets: Vec<Sheet>,
+}
+
+impl Sheet {
+    pub fn new(path: std::path::PathBuf) -> Self {
+        Self {
+            path,
+            grid: None,
+            manifest: None,
+            offset: (0, 0),
+            compression: Default::default(),
+            dither: None,
+            anchor: None,
+            hotspot_layer: None,
+        }
+    }
+}
+
+impl Bgf {
+    // A conf with nothing but a single sheet in it, named after the sheet.
+    pub fn from_sheet(sheet: Sheet) -> Self {
+        Self {
+            version: crate::bgf::CURRENT_BGF_VERSION,
+            name: sheet
+                .path
+                .file_stem()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            bitmaps: Vec::new(),
+            index_groups: Vec::new(),
+            shrink_factor: 1,
+            diffusion_kernel: None,
+            dither: None,
+            temporal: false,
+            trim: false,
+            dedupe: false,
+            anchor: None,
+            hotspot_markers: Vec::new(),
+            sheets: vec![sheet],
+        }
+    }
+
+    // Turn the sheets into plain bitmaps and index groups.
+    pub fn expand_sheets(&mut self, conf_dir: &std::path::Path) -> Result<()> {
+        for sheet in std::mem::take(&mut self.sheets) {
+            let (bitmaps, groups) = if crate::aseprite::is_aseprite(&sheet.path) {
+                crate::aseprite::slice(&sheet, conf_dir)?
+            } else if sheet.grid.is_none()
+                && sheet.manifest.is_none()
+                && crate::animate::is_animation(&sheet.path)
+            {
+                crate::animate::slice(&sheet, conf_dir)?
+            } else {
+                crate::atlas::slice(&sheet, conf_dir)?
+            };
+            let first = self.bitmaps.len();
+
+            self.bitmaps.extend(bitmaps);
+            self.index_groups
+                .extend(groups.into_iter().map(|indices| Group {
+                    indices: indices.into_iter().map(|i| (first + i) as i32).collect(),
+                }));
+        }
+
+        Ok(())
+    }
 }
 
 impl From<crate::bgf::Bgf> for Bgf {
@@ -87,6 +492,36 @@ impl From<crate::bgf::Bgf> for Bgf {
             bitmaps: value.bitmaps.into_iter().map(|b| b.into()).collect(),
             index_groups: value.index_groups.into_iter().map(|g| g.into()).collect(),
             shrink_factor: value.shrink_factor,
+            diffusion_kernel: None,
+            dither: None,
+            temporal: false,
+            trim: false,
+            dedupe: false,
+            anchor: None,
+            hotspot_markers: Vec::new(),
+            sheets: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blue_noise_size_is_used_as_given() {
+        for (size, valid) in [(3, false), (4, true), (64, true), (65, false)] {
+            let mut options = crate::bgf::BitmapImageOptions::default();
+            let result = DitherSettings {
+                blue_noise_size: Some(size),
+                ..Default::default()
+            }
+            .apply(&mut options);
+
+            assert_eq!(result.is_ok(), valid, "size {size}");
+            if valid {
+                assert_eq!(options.blue_noise_size, size);
+            }
         }
     }
 }
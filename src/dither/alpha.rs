@@ -0,0 +1,96 @@
+// Helpers for deciding which pixels end up transparent, and for cleaning up
+// the colors of semi-transparent edge pixels before they are quantized.
+
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlphaMode {
+    // Pixels below the transparency value are cut out.
+    #[default]
+    Clip,
+    // Pick the transparency value per image with Otsu's method.
+    Otsu,
+    // Cut out pixels against an ordered pattern, so edges keep roughly the
+    // same coverage as the source.
+    Dither,
+}
+
+// Undo the blending of semi-transparent pixels against a matte color. A black
+// matte is the same as un-premultiplying the colors.
+pub fn remove_matte(image_buffer: &mut image::Rgba32FImage, matte: [f32; 3]) {
+    image_buffer.pixels_mut().for_each(|pixel| {
+        let alpha = pixel[3];
+
+        if alpha <= 0.0 || alpha >= 1.0 {
+            return;
+        }
+
+        pixel.0[..3]
+            .iter_mut()
+            .zip(matte)
+            .for_each(|(v, m)| *v = ((*v - (1.0 - alpha) * m) / alpha).clamp(0.0, 1.0));
+    });
+}
+
+// Otsu's method on the alpha channel, returning the value that best splits the
+// image into transparent and opaque pixels.
+pub fn otsu_threshold(image_buffer: &image::Rgba32FImage) -> Option<f32> {
+    let mut histogram = [0usize; 256];
+
+    for pixel in image_buffer.pixels() {
+        histogram[(pixel[3].clamp(0.0, 1.0) * 255.0) as usize] += 1;
+    }
+
+    let total = image_buffer.pixels().len() as f64;
+    let total_sum = histogram
+        .iter()
+        .enumerate()
+        .map(|(i, count)| i as f64 * *count as f64)
+        .sum::<f64>();
+    let mut background_count = 0.0;
+    let mut background_sum = 0.0;
+    let mut best = None;
+    let mut best_variance = 0.0;
+
+    for (i, count) in histogram.iter().enumerate() {
+        background_count += *count as f64;
+        background_sum += i as f64 * *count as f64;
+        let foreground_count = total - background_count;
+
+        if background_count == 0.0 || foreground_count == 0.0 {
+            continue;
+        }
+
+        let background_mean = background_sum / background_count;
+        let foreground_mean = (total_sum - background_sum) / foreground_count;
+        let variance =
+            background_count * foreground_count * (background_mean - foreground_mean).powi(2);
+
+        if variance > best_variance {
+            best_variance = variance;
+            best = Some(i);
+        }
+    }
+
+    // Everything at or below the split is transparent.
+    best.map(|i| (i + 1) as f32 / 255.0)
+}
+
+// 8x8 Bayer threshold, between 0.0 and 1.0 (exclusive), so fully opaque
+// pixels are always kept and fully transparent pixels are always cut out.
+#[inline(always)]
+pub fn ordered_threshold(x: usize, y: usize) -> f32 {
+    static BAYER: std::sync::LazyLock<super::ordered::BayerMatrixGenerator> =
+        std::sync::LazyLock::new(|| super::ordered::BayerMatrixGenerator::new(8));
+
+    BAYER.get(x, y)
+}
@@ -1,13 +1,32 @@
-use image::{ImageBuffer, Pixel, imageops::ColorMap};
+use image::{ImageBuffer, Pixel};
 use rayon::prelude::*;
 
+pub mod alpha;
+mod ordered;
 mod pcg_noise;
+pub mod quality;
 mod r2_blue_noise;
+pub mod registry;
+mod riemersma;
+pub mod temporal;
+mod void_and_cluster;
+mod wavefront;
 
 // Error diffusion dithering based on
 // https://tannerhelland.com/2012/12/28/dithering-eleven-algorithms-source-code.html
 
-#[derive(Debug, Default, Clone, Copy, clap::ValueEnum)]
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
 pub enum DitherOptions {
     #[default]
     None,
@@ -21,6 +40,45 @@ pub enum DitherOptions {
     Sierra,
     TwoRowSierra,
     SierraLite,
+    Bayer2x2,
+    Bayer4x4,
+    Bayer8x8,
+    Yliluoma,
+    BlueNoise,
+    Custom,
+    Riemersma,
+    Auto,
+}
+
+// What to do with diffused error that falls outside of the image.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum BorderPolicy {
+    #[default]
+    Drop,
+    Clamp,
+    Wrap,
+}
+
+// Anything that can turn an image into palette indices. Implement this and add
+// it to the `registry` to make a dither selectable by name.
+pub trait Ditherer: Send + Sync {
+    fn dither(
+        &self,
+        image_buffer: &image::Rgba32FImage,
+        options: &crate::bgf::BitmapImageOptions,
+        palette: &crate::bgf::Palette,
+    ) -> Vec<u8>;
 }
 
 pub enum DitherGenerator {
@@ -35,6 +93,11 @@ pub enum DitherGenerator {
     Sierra,
     TwoRowSierra,
     SierraLite,
+    Bayer(ordered::BayerMatrixGenerator),
+    Yliluoma(ordered::YliluomaMixer),
+    BlueNoise(void_and_cluster::VoidAndClusterGenerator<4>),
+    Custom(Vec<(f32, (isize, isize))>),
+    Riemersma(riemersma::RiemersmaGenerator),
 }
 
 impl DitherGenerator {
@@ -82,35 +145,100 @@ impl DitherGenerator {
         Self::SierraLite
     }
 
+    pub fn new_bayer(size: usize) -> Self {
+        Self::Bayer(ordered::BayerMatrixGenerator::new(size))
+    }
+
+    pub fn new_yliluoma() -> Self {
+        Self::Yliluoma(ordered::YliluomaMixer::new())
+    }
+
+    pub fn new_blue_noise(size: usize, offset: (usize, usize)) -> Self {
+        Self::BlueNoise(void_and_cluster::VoidAndClusterGenerator::new(size, offset))
+    }
+
+    pub fn new_custom(kernel: &crate::conf::DiffusionKernel) -> Self {
+        Self::Custom(kernel.diffusion())
+    }
+
+    pub fn new_riemersma(history_len: usize) -> Self {
+        Self::Riemersma(riemersma::RiemersmaGenerator::new(history_len))
+    }
+
+    pub fn from_options(
+        options: &crate::bgf::BitmapImageOptions,
+        size: (u32, u32),
+    ) -> color_eyre::eyre::Result<Self> {
+        let generator = match options.dither {
+            DitherOptions::None => Self::new_none(),
+            DitherOptions::R2 => Self::new_r2((options.dither_seed as f64 * R2_SEED_SCALE).fract()),
+            DitherOptions::Pcg => {
+                // Noise is sampled across the whole canvas when frames share one.
+                let (width, height) = options.canvas_size.unwrap_or(size);
+                Self::new_pcg(options.dither_seed, (width * height) as usize)
+            }
+            DitherOptions::FloydSteinberg => Self::new_floyd_steinberg(),
+            DitherOptions::JavisJudiceNinke => Self::new_javis_judice_ninke(),
+            DitherOptions::Stucki => Self::new_stucki(),
+            DitherOptions::Atkinson => Self::new_atkinson(),
+            DitherOptions::Burkes => Self::new_burkes(),
+            DitherOptions::Sierra => Self::new_sierra(),
+            DitherOptions::TwoRowSierra => Self::new_two_row_sierra(),
+            DitherOptions::SierraLite => Self::new_sierra_lite(),
+            DitherOptions::Bayer2x2 => Self::new_bayer(2),
+            DitherOptions::Bayer4x4 => Self::new_bayer(4),
+            DitherOptions::Bayer8x8 => Self::new_bayer(8),
+            DitherOptions::Yliluoma => Self::new_yliluoma(),
+            DitherOptions::BlueNoise => {
+                Self::new_blue_noise(options.blue_noise_size, options.blue_noise_offset)
+            }
+            DitherOptions::Custom => {
+                let kernel = options.diffusion_kernel.as_ref().ok_or_else(|| {
+                    color_eyre::eyre::eyre!("Custom dither requires a diffusion kernel.")
+                })?;
+                kernel.validate()?;
+
+                Self::new_custom(kernel)
+            }
+            DitherOptions::Riemersma => Self::new_riemersma(options.riemersma_history),
+            DitherOptions::Auto => {
+                return Err(color_eyre::eyre::eyre!(
+                    "Auto dither has to be resolved to an algorithm first."
+                ));
+            }
+        };
+
+        Ok(generator)
+    }
+
     pub fn dither(
         &self,
         image_buffer: &image::ImageBuffer<image::Rgba<f32>, Vec<f32>>,
         options: &crate::bgf::BitmapImageOptions,
         palette: &crate::bgf::Palette,
     ) -> Vec<u8> {
-        let (transparent_index, transparent_color) = palette.transparent_color();
-        let transparent_color_f = image::Rgb([
-            byte_to_float(transparent_color[0]),
-            byte_to_float(transparent_color[1]),
-            byte_to_float(transparent_color[2]),
-        ]);
+        let (transparent_index, _) = palette.transparent_color();
+        let (image_buffer, options, transparent_color_f) = prepare(image_buffer, options, palette);
+        let (image_buffer, options) = (image_buffer.as_ref(), options.as_ref());
+        let width = image_buffer.width() as usize;
 
         match self {
             Self::None => image_buffer
                 .par_pixels()
-                .map(|pixel| {
-                    if pixel[3] < options.transparency_clip || pixel.to_rgb() == transparent_color_f
-                    {
+                .enumerate()
+                .map(|(index, pixel)| {
+                    if let Some(color_index) = locked_index(options, index) {
+                        color_index
+                    } else if is_transparent(
+                        options,
+                        pixel,
+                        canvas_position(options, index, width).1,
+                        transparent_color_f,
+                    ) {
                         transparent_index as u8
                     } else {
-                        let color_f = pixel.to_rgb();
-                        let color = image::Rgb([
-                            float_to_byte(color_f[0]),
-                            float_to_byte(color_f[1]),
-                            float_to_byte(color_f[2]),
-                        ]);
-                        let color_index = palette.index_of(&color);
-                        color_index as u8
+                        let (color_index, _) = closest_color(palette, options, pixel.to_rgb());
+                        color_index
                     }
                 })
                 .collect::<Vec<_>>(),
@@ -120,7 +248,7 @@ impl DitherGenerator {
                 transparent_index,
                 options,
                 palette,
-                |index| r2_blue_noise_generator.get(index),
+                |index, _| r2_blue_noise_generator.get(index),
             ),
             Self::Pcg(pcg_noise_generator) => dither_from_noise(
                 image_buffer,
@@ -128,7 +256,15 @@ impl DitherGenerator {
                 transparent_index,
                 options,
                 palette,
-                |index| pcg_noise_generator.get(index),
+                |index, _| pcg_noise_generator.get(index),
+            ),
+            Self::BlueNoise(void_and_cluster_generator) => dither_from_noise(
+                image_buffer,
+                transparent_color_f,
+                transparent_index,
+                options,
+                palette,
+                |_, (x, y)| void_and_cluster_generator.get(x, y),
             ),
             Self::FloydSteinberg => dither_from_error_diffusion(
                 image_buffer,
@@ -243,7 +379,7 @@ impl DitherGenerator {
                 palette,
                 &[
                     (4.0 / 16.0, (1, 0)),
-                    (3.0 / 16.0, (1, 0)),
+                    (3.0 / 16.0, (2, 0)),
                     (1.0 / 16.0, (-2, 1)),
                     (2.0 / 16.0, (-1, 1)),
                     (3.0 / 16.0, (0, 1)),
@@ -263,10 +399,71 @@ impl DitherGenerator {
                     (1.0 / 4.0, (0, 1)),
                 ],
             ),
+            Self::Bayer(bayer_matrix_generator) => dither_from_ordered(
+                image_buffer,
+                transparent_color_f,
+                transparent_index,
+                options,
+                palette,
+                |x, y| bayer_matrix_generator.get(x, y),
+            ),
+            Self::Yliluoma(yliluoma_mixer) => dither_from_mixing_plan(
+                image_buffer,
+                transparent_color_f,
+                transparent_index,
+                options,
+                palette,
+                yliluoma_mixer,
+            ),
+            Self::Custom(diffusion) => dither_from_error_diffusion(
+                image_buffer,
+                transparent_color_f,
+                transparent_index,
+                options,
+                palette,
+                diffusion,
+            ),
+            Self::Riemersma(riemersma_generator) => dither_from_space_filling_curve(
+                image_buffer,
+                transparent_color_f,
+                transparent_index,
+                options,
+                palette,
+                riemersma_generator,
+            ),
         }
     }
 }
 
+impl Ditherer for DitherGenerator {
+    fn dither(
+        &self,
+        image_buffer: &image::Rgba32FImage,
+        options: &crate::bgf::BitmapImageOptions,
+        palette: &crate::bgf::Palette,
+    ) -> Vec<u8> {
+        DitherGenerator::dither(self, image_buffer, options, palette)
+    }
+}
+
+// Void-and-cluster textures take time with the square of their pixel count to
+// generate, so they're kept small. They tile, so this is plenty. Anything
+// smaller than the minimum is too few pixels to spread out.
+pub const MIN_BLUE_NOISE_SIZE: usize = 4;
+pub const MAX_BLUE_NOISE_SIZE: usize = 64;
+
+// The R2 sequence only uses the fractional part of the seed, so spread integer
+// seeds out by the golden ratio.
+const R2_SEED_SCALE: f64 = 0.618_033_988_749_895;
+
+// Images smaller than this aren't worth spreading error diffusion over
+// multiple threads.
+const WAVEFRONT_MIN_PIXELS: usize = 256 * 256;
+
+// How far (in normalized RGB units) an ordered threshold can push a color
+// before it is matched against the palette.
+const ORDERED_SPREAD: f32 = 1.0 / 8.0;
+
 #[inline(always)]
 fn float_to_byte(value: f32) -> u8 {
     (value.clamp(0.0, 1.0) * 255.0) as u8
@@ -278,21 +475,282 @@ fn byte_to_float(value: u8) -> f32 {
 }
 
 #[inline(always)]
-fn propagate_error(
-    value: image::Rgba<f32>,
+fn locked_index(options: &crate::bgf::BitmapImageOptions, index: usize) -> Option<u8> {
+    options
+        .locked_indices
+        .as_ref()
+        .and_then(|locked| locked.get(index).copied().flatten())
+}
+
+// Position of a pixel once it has been moved onto the canvas, along with its
+// linear index on that canvas. Noise is sampled from here so it stays put when
+// a frame moves around on a shared canvas.
+#[inline(always)]
+fn canvas_position(
+    options: &crate::bgf::BitmapImageOptions,
+    index: usize,
+    width: usize,
+) -> (usize, (usize, usize)) {
+    let x = index % width + options.canvas_origin.0;
+    let y = index / width + options.canvas_origin.1;
+    let stride = options
+        .canvas_size
+        .map(|(canvas_width, _)| canvas_width as usize)
+        .unwrap_or(width);
+
+    (y * stride + x, (x, y))
+}
+
+#[inline(always)]
+fn is_transparent(
+    options: &crate::bgf::BitmapImageOptions,
+    pixel: &image::Rgba<f32>,
+    canvas_position: (usize, usize),
+    transparent_color_f: image::Rgb<f32>,
+) -> bool {
+    let cutoff = match options.alpha {
+        alpha::AlphaMode::Clip | alpha::AlphaMode::Otsu => options.transparency_clip,
+        alpha::AlphaMode::Dither => alpha::ordered_threshold(canvas_position.0, canvas_position.1),
+    };
+
+    pixel[3] < cutoff || pixel.to_rgb() == transparent_color_f
+}
+
+#[inline(always)]
+fn locked_color(
+    palette: &crate::bgf::Palette,
+    options: &crate::bgf::BitmapImageOptions,
+    transparent_index: usize,
+    color_index: u8,
+) -> image::Rgba<f32> {
+    if color_index as usize == transparent_index {
+        image::Rgba([0.0, 0.0, 0.0, 0.0])
+    } else if options.linear_light {
+        palette.linear_values()[color_index as usize].to_rgba()
+    } else {
+        let color = palette.values()[color_index as usize];
+        image::Rgba([
+            byte_to_float(color[0]),
+            byte_to_float(color[1]),
+            byte_to_float(color[2]),
+            1.0,
+        ])
+    }
+}
+
+// The image the way the dithers work on it, along with the options they use and
+// the transparent color in the same color space.
+fn prepare<'a>(
+    image_buffer: &'a image::Rgba32FImage,
+    options: &'a crate::bgf::BitmapImageOptions,
+    palette: &crate::bgf::Palette,
+) -> (
+    std::borrow::Cow<'a, image::Rgba32FImage>,
+    std::borrow::Cow<'a, crate::bgf::BitmapImageOptions>,
+    image::Rgb<f32>,
+) {
+    let (_, transparent_color) = palette.transparent_color();
+    let mut transparent_color_f = image::Rgb([
+        byte_to_float(transparent_color[0]),
+        byte_to_float(transparent_color[1]),
+        byte_to_float(transparent_color[2]),
+    ]);
+    let mut image_buffer = std::borrow::Cow::Borrowed(image_buffer);
+
+    if let Some(matte) = options.matte {
+        alpha::remove_matte(image_buffer.to_mut(), matte.map(byte_to_float));
+    }
+
+    // Error is diffused and colors are compared in linear light when asked, so
+    // mid-tones don't get pulled darker.
+    if options.linear_light {
+        image_buffer.to_mut().pixels_mut().for_each(|pixel| {
+            pixel.0[..3]
+                .iter_mut()
+                .for_each(|v| *v = srgb_to_linear(*v));
+        });
+        transparent_color_f = transparent_color_f.map(srgb_to_linear);
+    }
+
+    // Resolve the automatic threshold once, so the dithering only has to deal
+    // with a fixed cutoff.
+    let options = if options.alpha == alpha::AlphaMode::Otsu {
+        std::borrow::Cow::Owned(crate::bgf::BitmapImageOptions {
+            alpha: alpha::AlphaMode::Clip,
+            transparency_clip: alpha::otsu_threshold(&image_buffer)
+                .unwrap_or(options.transparency_clip),
+            ..options.clone()
+        })
+    } else {
+        std::borrow::Cow::Borrowed(options)
+    };
+
+    (image_buffer, options, transparent_color_f)
+}
+
+// Lock the pixels that shouldn't be dithered on top of any existing locks.
+// Opaque pixels that are already an exact palette color keep it, and pixels
+// outside of the dither mask get their closest color. This is worked out before
+// dithering, so it applies to any ditherer.
+pub fn protected_indices(
+    image_buffer: &image::Rgba32FImage,
+    options: &crate::bgf::BitmapImageOptions,
+    palette: &crate::bgf::Palette,
+) -> Option<Vec<Option<u8>>> {
+    if !options.protect_exact && options.dither_mask.is_none() {
+        return None;
+    }
+
+    let (prepared_buffer, options, transparent_color_f) = prepare(image_buffer, options, palette);
+
+    protected_pixels(
+        image_buffer,
+        &prepared_buffer,
+        transparent_color_f,
+        &options,
+        palette,
+    )
+}
+
+fn protected_pixels(
+    source_buffer: &ImageBuffer<image::Rgba<f32>, Vec<f32>>,
+    image_buffer: &ImageBuffer<image::Rgba<f32>, Vec<f32>>,
+    transparent_color_f: image::Rgb<f32>,
+    options: &crate::bgf::BitmapImageOptions,
+    palette: &crate::bgf::Palette,
+) -> Option<Vec<Option<u8>>> {
+    if !options.protect_exact && options.dither_mask.is_none() {
+        return None;
+    }
+
+    let (transparent_index, _) = palette.transparent_color();
+    let width = image_buffer.width() as usize;
+    let mut exact_colors = std::collections::HashMap::new();
+
+    for (index, color) in palette.values().iter().enumerate().rev() {
+        if index != transparent_index {
+            exact_colors.insert(color.0, index as u8);
+        }
+    }
+
+    let locked_indices = source_buffer
+        .par_pixels()
+        .zip(image_buffer.par_pixels())
+        .enumerate()
+        .map(|(index, (source, pixel))| {
+            if let Some(color_index) = locked_index(options, index) {
+                return Some(color_index);
+            }
+
+            let masked = options
+                .dither_mask
+                .as_ref()
+                .is_some_and(|mask| !mask.get(index).copied().unwrap_or(true));
+
+            if masked {
+                let canvas_position = canvas_position(options, index, width).1;
+
+                return Some(
+                    if is_transparent(options, pixel, canvas_position, transparent_color_f) {
+                        transparent_index as u8
+                    } else {
+                        closest_color(palette, options, pixel.to_rgb()).0
+                    },
+                );
+            }
+
+            if !options.protect_exact || source[3] < 1.0 {
+                return None;
+            }
+
+            let color = [0, 1, 2].map(|i| (source[i].clamp(0.0, 1.0) * 255.0).round() as u8);
+
+            if (0..3).any(|i| (byte_to_float(color[i]) - source[i]).abs() > f32::EPSILON) {
+                return None;
+            }
+
+            exact_colors.get(&color).copied()
+        })
+        .collect::<Vec<_>>();
+
+    locked_indices
+        .iter()
+        .any(Option::is_some)
+        .then_some(locked_indices)
+}
+
+// Find the closest palette color to a color in the working space (sRGB or
+// linear light), returning the palette color in that same space.
+#[inline(always)]
+fn closest_color(
+    palette: &crate::bgf::Palette,
+    options: &crate::bgf::BitmapImageOptions,
+    color_f: image::Rgb<f32>,
+) -> (u8, image::Rgb<f32>) {
+    if options.linear_light {
+        let (color_index, color) = palette.find_closest_linear(&color_f);
+        (color_index as u8, *color)
+    } else {
+        let color = image::Rgb([
+            float_to_byte(color_f[0]),
+            float_to_byte(color_f[1]),
+            float_to_byte(color_f[2]),
+        ]);
+        let (color_index, color) = palette.find_closest(&color);
+        (
+            color_index as u8,
+            image::Rgb([
+                byte_to_float(color[0]),
+                byte_to_float(color[1]),
+                byte_to_float(color[2]),
+            ]),
+        )
+    }
+}
+
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[inline(always)]
+fn error_target(
     position: (isize, isize),
     size: (usize, usize),
-    buf: &mut [image::Rgba<f32>],
-) {
-    if position.0 >= size.0 as isize
-        || position.1 >= size.1 as isize
-        || position.0 <= 0_isize
-        || position.1 <= 0_isize
-    {
-        return;
+    border: BorderPolicy,
+) -> Option<usize> {
+    let (width, height) = (size.0 as isize, size.1 as isize);
+    let position = match border {
+        BorderPolicy::Drop => position,
+        BorderPolicy::Clamp => (
+            position.0.clamp(0, width - 1),
+            position.1.clamp(0, height - 1),
+        ),
+        // Rows that wrap are handled by the caller, since they have already
+        // been visited.
+        BorderPolicy::Wrap => (position.0.rem_euclid(width), position.1),
+    };
+
+    if position.0 >= width || position.1 >= height || position.0 < 0 || position.1 < 0 {
+        return None;
     }
 
-    let index = (position.1 as usize * size.0) + position.0 as usize;
+    Some((position.1 as usize * size.0) + position.0 as usize)
+}
+
+#[inline(always)]
+fn propagate_error(value: image::Rgba<f32>, index: usize, buf: &mut [image::Rgba<f32>]) {
     let old_error = buf[index];
     let new_error = image::Rgba([
         old_error[0] + value[0],
@@ -312,34 +770,35 @@ fn dither_from_noise<G>(
     generator: G,
 ) -> Vec<u8>
 where
-    G: FnOnce(usize) -> [f32; 4] + Sync + Send + Copy,
+    G: FnOnce(usize, (usize, usize)) -> [f32; 4] + Sync + Send + Copy,
 {
+    let width = image_buffer.width() as usize;
+
     image_buffer
         .par_pixels()
         .enumerate()
         .map(|(index, pixel)| {
-            let noise = generator(index);
+            if let Some(color_index) = locked_index(options, index) {
+                return color_index;
+            }
+
+            let (canvas_index, canvas_position) = canvas_position(options, index, width);
+            let noise = generator(canvas_index, canvas_position);
             let pixel = {
                 let mut pixel = pixel.0;
                 pixel
                     .iter_mut()
                     .enumerate()
-                    .for_each(|(i, v)| *v += *v * noise[i]);
+                    .for_each(|(i, v)| *v += *v * noise[i] * options.dither_strength);
 
                 image::Rgba(pixel)
             };
 
-            if pixel[3] < options.transparency_clip || pixel.to_rgb() == transparent_color_f {
+            if is_transparent(options, &pixel, canvas_position, transparent_color_f) {
                 transparent_index as u8
             } else {
-                let color_f = pixel.to_rgb();
-                let color = image::Rgb([
-                    float_to_byte(color_f[0]),
-                    float_to_byte(color_f[1]),
-                    float_to_byte(color_f[2]),
-                ]);
-                let color_index = palette.index_of(&color);
-                color_index as u8
+                let (color_index, _) = closest_color(palette, options, pixel.to_rgb());
+                color_index
             }
         })
         .collect::<Vec<_>>()
@@ -353,65 +812,676 @@ fn dither_from_error_diffusion(
     palette: &crate::bgf::Palette,
     diffusion: &[(f32, (isize, isize))],
 ) -> Vec<u8> {
-    let mut error_buf =
-        vec![image::Rgba([0.0f32; 4]); (image_buffer.width() * image_buffer.height()) as usize];
+    let width = image_buffer.width() as usize;
+    let height = image_buffer.height() as usize;
+
+    // Wrapping keeps the error that falls off the bottom in extra rows, and
+    // does a second pass with it fed back in at the top so the result tiles.
+    let (passes, extra_rows) = if options.border == BorderPolicy::Wrap {
+        let extra_rows = diffusion
+            .iter()
+            .map(|(_, (_, y))| *y as usize)
+            .max()
+            .unwrap_or(0);
+        (2, extra_rows)
+    } else {
+        (1, 0)
+    };
+    let buffer_size = (width, height + extra_rows);
+
+    // Mask-aware diffusion decides transparency from the source alone, and
+    // only spreads error between opaque pixels.
+    let transparent_mask = options.mask_diffusion.then(|| {
+        image_buffer
+            .par_pixels()
+            .enumerate()
+            .map(|(index, pixel)| match locked_index(options, index) {
+                Some(color_index) => color_index as usize == transparent_index,
+                None => is_transparent(
+                    options,
+                    pixel,
+                    canvas_position(options, index, width).1,
+                    transparent_color_f,
+                ),
+            })
+            .collect::<Vec<_>>()
+    });
+
+    // Quantize a single pixel along with the error it has gathered so far,
+    // handing out what is left over with `propagate`.
+    let diffuse_pixel = |(x, y): (usize, usize),
+                         reverse: bool,
+                         error: image::Rgba<f32>,
+                         propagate: &mut dyn FnMut(image::Rgba<f32>, usize)|
+     -> u8 {
+        let index = y * width + x;
+        let pixel = image_buffer.get_pixel(x as u32, y as u32);
+        let pixel = image::Rgba([
+            pixel[0] + error[0],
+            pixel[1] + error[1],
+            pixel[2] + error[2],
+            pixel[3] + error[3],
+        ]);
+
+        let transparent = match &transparent_mask {
+            Some(mask) => mask[index],
+            None => is_transparent(
+                options,
+                &pixel,
+                canvas_position(options, index, width).1,
+                transparent_color_f,
+            ),
+        };
+
+        let (color_index, next_color) = if let Some(color_index) = locked_index(options, index) {
+            (
+                color_index,
+                locked_color(palette, options, transparent_index, color_index),
+            )
+        } else if transparent {
+            (transparent_index as u8, image::Rgba([0.0, 0.0, 0.0, 0.0]))
+        } else {
+            let (color_index, next_color) = closest_color(palette, options, pixel.to_rgb());
+            (
+                color_index,
+                image::Rgba([next_color[0], next_color[1], next_color[2], 1.0]),
+            )
+        };
+        let diff = image::Rgba([
+            pixel[0] - next_color[0],
+            pixel[1] - next_color[1],
+            pixel[2] - next_color[2],
+            pixel[3] - next_color[3],
+        ]);
+
+        let targets = diffusion.iter().filter_map(|(fract, rel_position)| {
+            let rel_x = if reverse {
+                -rel_position.0
+            } else {
+                rel_position.0
+            };
+            let position = (rel_x + x as isize, rel_position.1 + y as isize);
+            error_target(position, buffer_size, options.border).map(|t| (*fract, t))
+        });
+
+        let Some(mask) = &transparent_mask else {
+            for (fract, target) in targets {
+                propagate(diff.map(|v| v * fract * options.dither_strength), target);
+            }
+
+            return color_index;
+        };
+
+        if transparent {
+            return color_index;
+        }
+
+        // Give the share of any transparent neighbors to the opaque ones, so
+        // the error isn't lost along the silhouette. Extra rows from wrapping
+        // map back onto the top of the image.
+        let targets = targets
+            .filter(|(_, target)| !mask[target % (width * height)])
+            .collect::<Vec<_>>();
+        let total = diffusion.iter().map(|(fract, _)| fract).sum::<f32>();
+        let opaque_total = targets.iter().map(|(fract, _)| fract).sum::<f32>();
+
+        // None of the opaque neighbors take any error, so it has nowhere to go.
+        if opaque_total == 0.0 {
+            return color_index;
+        }
+
+        let diff = image::Rgba([diff[0], diff[1], diff[2], 0.0]);
+
+        for (fract, target) in targets {
+            propagate(
+                diff.map(|v| v * fract / opaque_total * total * options.dither_strength),
+                target,
+            );
+        }
+
+        color_index
+    };
+
+    // Wrapped error has to come back around to rows that are already done, so
+    // that is always done serially.
+    if passes == 1 && width * height >= WAVEFRONT_MIN_PIXELS && rayon::current_num_threads() > 1 {
+        let reach = diffusion
+            .iter()
+            .map(|(_, (x, _))| x.unsigned_abs())
+            .max()
+            .unwrap_or(0);
+
+        return wavefront::diffuse(
+            (width, height),
+            reach,
+            options.serpentine,
+            transparent_index as u8,
+            diffuse_pixel,
+        );
+    }
+
+    let mut indices = vec![transparent_index as u8; width * height];
+    let mut wrapped_error = vec![image::Rgba([0.0f32; 4]); width * extra_rows];
+    // Error that wraps around the side onto pixels already done in the same
+    // row, kept for the next pass like the rows that wrap.
+    let mut carried_error = vec![image::Rgba([0.0f32; 4]); width * height];
+
+    for _ in 0..passes {
+        let mut error_buf = std::mem::replace(
+            &mut carried_error,
+            vec![image::Rgba([0.0f32; 4]); width * height],
+        );
+        error_buf.resize(width * buffer_size.1, image::Rgba([0.0f32; 4]));
+
+        for (index, error) in wrapped_error.iter().enumerate().take(width * height) {
+            propagate_error(*error, index, &mut error_buf);
+        }
+
+        for y in 0..height {
+            // Serpentine scanning walks every other row from right to left, and
+            // mirrors the kernel so the error still lands on unvisited pixels.
+            let reverse = options.serpentine && y % 2 == 1;
+
+            for step in 0..width {
+                let x = if reverse { width - 1 - step } else { step };
+                let index = y * width + x;
+                indices[index] =
+                    diffuse_pixel((x, y), reverse, error_buf[index], &mut |value, target| {
+                        let visited = passes > 1
+                            && target / width == y
+                            && if reverse {
+                                target % width >= x
+                            } else {
+                                target % width <= x
+                            };
+
+                        if visited {
+                            propagate_error(value, target, &mut carried_error)
+                        } else {
+                            propagate_error(value, target, &mut error_buf)
+                        }
+                    });
+            }
+        }
+
+        wrapped_error = error_buf.split_off(width * height);
+    }
+
+    indices
+}
+
+fn dither_from_ordered<G>(
+    image_buffer: &ImageBuffer<image::Rgba<f32>, Vec<f32>>,
+    transparent_color_f: image::Rgb<f32>,
+    transparent_index: usize,
+    options: &crate::bgf::BitmapImageOptions,
+    palette: &crate::bgf::Palette,
+    threshold: G,
+) -> Vec<u8>
+where
+    G: Fn(usize, usize) -> f32 + Sync + Send,
+{
+    let width = image_buffer.width() as usize;
 
     image_buffer
+        .par_pixels()
+        .enumerate()
+        .map(|(index, pixel)| {
+            if let Some(color_index) = locked_index(options, index) {
+                return color_index;
+            }
+
+            let (_, (x, y)) = canvas_position(options, index, width);
+
+            if is_transparent(options, pixel, (x, y), transparent_color_f) {
+                return transparent_index as u8;
+            }
+
+            let bias = (threshold(x, y) - 0.5) * ORDERED_SPREAD * options.dither_strength;
+            let (color_index, _) =
+                closest_color(palette, options, pixel.to_rgb().map(|v| v + bias));
+            color_index
+        })
+        .collect::<Vec<_>>()
+}
+
+fn dither_from_mixing_plan(
+    image_buffer: &ImageBuffer<image::Rgba<f32>, Vec<f32>>,
+    transparent_color_f: image::Rgb<f32>,
+    transparent_index: usize,
+    options: &crate::bgf::BitmapImageOptions,
+    palette: &crate::bgf::Palette,
+    mixer: &ordered::YliluomaMixer,
+) -> Vec<u8> {
+    let width = image_buffer.width() as usize;
+    let to_color = |pixel: &image::Rgba<f32>| {
+        [
+            (pixel[0].clamp(0.0, 1.0) * 65535.0) as u16,
+            (pixel[1].clamp(0.0, 1.0) * 65535.0) as u16,
+            (pixel[2].clamp(0.0, 1.0) * 65535.0) as u16,
+        ]
+    };
+    // Plans are mixed in the same space as the image, so the palette needs to
+    // match it.
+    let palette_colors = if options.linear_light {
+        palette
+            .linear_values()
+            .iter()
+            .map(|c| [c[0] * 255.0, c[1] * 255.0, c[2] * 255.0])
+            .collect::<Vec<_>>()
+    } else {
+        palette
+            .values()
+            .iter()
+            .map(|c| [c[0] as f32, c[1] as f32, c[2] as f32])
+            .collect::<Vec<_>>()
+    };
+
+    let transparent = image_buffer
+        .par_pixels()
+        .enumerate()
+        .map(|(index, pixel)| {
+            is_transparent(
+                options,
+                pixel,
+                canvas_position(options, index, width).1,
+                transparent_color_f,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // Building a mixing plan is expensive, so only do it once per unique
+    // color. Sprites rarely use more than a few thousand colors.
+    let mut colors = image_buffer
         .pixels()
+        .zip(&transparent)
+        .filter(|(_, transparent)| !**transparent)
+        .map(|(pixel, _)| to_color(pixel))
+        .collect::<Vec<_>>();
+    colors.sort_unstable();
+    colors.dedup();
+
+    let plans = colors
+        .into_par_iter()
+        .map(|color| {
+            let target = color.map(|c| c as f32 / 65535.0 * 255.0);
+            (
+                color,
+                mixer.mixing_plan(&target, &palette_colors, transparent_index),
+            )
+        })
+        .collect::<std::collections::HashMap<_, _>>();
+
+    image_buffer
+        .par_pixels()
         .enumerate()
         .map(|(index, pixel)| {
-            let x = index as isize % (image_buffer.width() as isize);
-            let y = index as isize / (image_buffer.width() as isize);
-            let error = error_buf[index];
-            let pixel = image::Rgba([
-                pixel[0] + error[0],
-                pixel[1] + error[1],
-                pixel[2] + error[2],
-                pixel[3] + error[3],
-            ]);
-
-            let (index, next_color) =
-                if pixel[3] < options.transparency_clip || pixel.to_rgb() == transparent_color_f {
-                    (transparent_index as u8, image::Rgba([0.0, 0.0, 0.0, 0.0]))
-                } else {
-                    let color_f = pixel.to_rgb();
-                    let color = image::Rgb([
-                        float_to_byte(color_f[0]),
-                        float_to_byte(color_f[1]),
-                        float_to_byte(color_f[2]),
-                    ]);
-                    let (color_index, next_color) = palette.find_closest(&color);
-                    (
-                        color_index as u8,
-                        image::Rgba([
-                            byte_to_float(next_color[0]),
-                            byte_to_float(next_color[1]),
-                            byte_to_float(next_color[2]),
-                            1.0,
-                        ]),
-                    )
-                };
-            let diff = image::Rgba([
-                pixel[0] - next_color[0],
-                pixel[1] - next_color[1],
-                pixel[2] - next_color[2],
-                pixel[3] - next_color[3],
-            ]);
-
-            for (fract, rel_position) in diffusion {
-                let position = (rel_position.0 + x, rel_position.1 + y);
-                propagate_error(
-                    diff.map(|v| v * fract),
-                    position,
-                    (
-                        image_buffer.width() as usize,
-                        image_buffer.height() as usize,
-                    ),
-                    &mut error_buf,
-                );
+            if let Some(color_index) = locked_index(options, index) {
+                return color_index;
+            }
+
+            if transparent[index] {
+                return transparent_index as u8;
+            }
+
+            let plan = &plans[&to_color(pixel)];
+            let (_, (x, y)) = canvas_position(options, index, width);
+            // Lower strengths pull the threshold towards the middle of the
+            // plan, which is the closest color to the original.
+            let threshold = 0.5 + (mixer.threshold(x, y) - 0.5) * options.dither_strength;
+            let plan_index =
+                ((threshold * ordered::YLILUOMA_PLAN_SIZE as f32) as usize).min(plan.len() - 1);
+            plan[plan_index] as u8
+        })
+        .collect::<Vec<_>>()
+}
+
+fn dither_from_space_filling_curve(
+    image_buffer: &ImageBuffer<image::Rgba<f32>, Vec<f32>>,
+    transparent_color_f: image::Rgb<f32>,
+    transparent_index: usize,
+    options: &crate::bgf::BitmapImageOptions,
+    palette: &crate::bgf::Palette,
+    generator: &riemersma::RiemersmaGenerator,
+) -> Vec<u8> {
+    let width = image_buffer.width() as usize;
+    let height = image_buffer.height() as usize;
+    let weights = generator.weights();
+    let mut history = std::collections::VecDeque::from(vec![[0.0f32; 4]; weights.len()]);
+    let mut indices = vec![transparent_index as u8; width * height];
+
+    for (x, y) in riemersma::hilbert_curve(width, height) {
+        let source = image_buffer.get_pixel(x as u32, y as u32);
+        let mut pixel = source.0;
+
+        for (error, weight) in history.iter().zip(weights) {
+            pixel
+                .iter_mut()
+                .zip(error)
+                .for_each(|(v, e)| *v += e * weight * options.dither_strength);
+        }
+
+        let pixel = image::Rgba(pixel);
+
+        // Transparent pixels don't record any error, so it can't leak out of
+        // transparent areas into the sprite along the curve.
+        let index = y * width + x;
+        let (color_index, error) = if let Some(color_index) = locked_index(options, index) {
+            if color_index as usize == transparent_index {
+                (color_index, [0.0; 4])
+            } else {
+                let color = locked_color(palette, options, transparent_index, color_index);
+                (
+                    color_index,
+                    [
+                        source[0] - color[0],
+                        source[1] - color[1],
+                        source[2] - color[2],
+                        source[3] - color[3],
+                    ],
+                )
             }
+        } else if is_transparent(
+            options,
+            &pixel,
+            canvas_position(options, index, width).1,
+            transparent_color_f,
+        ) {
+            (transparent_index as u8, [0.0; 4])
+        } else {
+            let (color_index, next_color) = closest_color(palette, options, pixel.to_rgb());
+            (
+                color_index,
+                [
+                    source[0] - next_color[0],
+                    source[1] - next_color[1],
+                    source[2] - next_color[2],
+                    source[3] - 1.0,
+                ],
+            )
+        };
+
+        history.pop_front();
+        history.push_back(error);
+        indices[index] = color_index;
+    }
+
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Horizontal ramps in each channel, fully opaque.
+    fn gradient(width: u32, height: u32) -> image::Rgba32FImage {
+        image::Rgba32FImage::from_fn(width, height, |x, y| {
+            let u = x as f32 / (width - 1) as f32;
+            let v = y as f32 / (height - 1) as f32;
+            image::Rgba([u, v, (u + v) / 2.0, 1.0])
+        })
+    }
+
+    fn gray_ramp(width: u32, height: u32) -> image::Rgba32FImage {
+        image::Rgba32FImage::from_fn(width, height, |x, _| {
+            let v = x as f32 / (width - 1) as f32;
+            image::Rgba([v, v, v, 1.0])
+        })
+    }
+
+    // Mean relative luminance, worked out in linear light.
+    fn mean_luminance<I>(colors: I) -> f32
+    where
+        I: ExactSizeIterator<Item = [f32; 3]>,
+    {
+        let count = colors.len() as f32;
+
+        colors
+            .map(|c| {
+                let [r, g, b] = c.map(srgb_to_linear);
+                0.2126 * r + 0.7152 * g + 0.0722 * b
+            })
+            .sum::<f32>()
+            / count
+    }
+
+    fn luminance_error(image_buffer: &image::Rgba32FImage, linear_light: bool) -> f32 {
+        let palette = crate::bgf::Palette::new();
+        let options = crate::bgf::BitmapImageOptions {
+            dither: DitherOptions::FloydSteinberg,
+            linear_light,
+            ..Default::default()
+        };
+        let indices =
+            DitherGenerator::new_floyd_steinberg().dither(image_buffer, &options, &palette);
+        let source = mean_luminance(image_buffer.pixels().map(|p| [p[0], p[1], p[2]]));
+        let output = mean_luminance(
+            indices
+                .iter()
+                .map(|i| palette.values()[*i as usize].0.map(byte_to_float)),
+        );
+
+        (source - output).abs()
+    }
 
-            index
+    #[test]
+    fn linear_light_preserves_luminance() {
+        let image_buffer = gradient(64, 64);
+        let linear = luminance_error(&image_buffer, true);
+        let srgb = luminance_error(&image_buffer, false);
+
+        assert!(linear < 0.006, "linear light is off by {linear}");
+        assert!(
+            linear * 2.0 < srgb,
+            "linear light is off by {linear}, sRGB by {srgb}"
+        );
+    }
+
+    #[test]
+    fn linear_light_preserves_gray_luminance() {
+        let image_buffer = gray_ramp(64, 64);
+        let linear = luminance_error(&image_buffer, true);
+        let srgb = luminance_error(&image_buffer, false);
+
+        assert!(linear < 0.001, "linear light is off by {linear}");
+        assert!(
+            linear < srgb,
+            "linear light is off by {linear}, sRGB by {srgb}"
+        );
+    }
+
+    #[test]
+    fn mask_diffusion_with_zero_weight_neighbors() {
+        // The only opaque neighbor has no weight, and the one below that would
+        // take the error is transparent.
+        let kernel = crate::conf::DiffusionKernel {
+            divisor: 1.0,
+            weights: vec![
+                crate::conf::DiffusionKernelEntry {
+                    weight: 0.0,
+                    offset: (1, 0),
+                },
+                crate::conf::DiffusionKernelEntry {
+                    weight: 1.0,
+                    offset: (0, 1),
+                },
+            ],
+        };
+        kernel.validate().unwrap();
+        let image_buffer = image::Rgba32FImage::from_fn(4, 2, |_, y| {
+            image::Rgba([0.37, 0.52, 0.61, if y == 0 { 1.0 } else { 0.0 }])
+        });
+        let options = crate::bgf::BitmapImageOptions {
+            dither: DitherOptions::Custom,
+            diffusion_kernel: Some(kernel),
+            mask_diffusion: true,
+            linear_light: true,
+            ..Default::default()
+        };
+
+        let indices = crate::bgf::Bitmap::quantize(&image_buffer, &options).unwrap();
+
+        // No error reaches the rest of the row, so it all comes out the same.
+        assert!(indices[..4].iter().all(|index| *index == indices[0]));
+    }
+
+    #[test]
+    fn wrap_tiles_horizontally() {
+        // Kernels that only push error along the row leave rows independent,
+        // so a wrapped row comes out the same as it does between two copies of
+        // itself.
+        let kernel = crate::conf::DiffusionKernel {
+            divisor: 3.0,
+            weights: vec![
+                crate::conf::DiffusionKernelEntry {
+                    weight: 2.0,
+                    offset: (1, 0),
+                },
+                crate::conf::DiffusionKernelEntry {
+                    weight: 1.0,
+                    offset: (2, 0),
+                },
+            ],
+        };
+        let (width, height) = (24, 6);
+        let tile = image::Rgba32FImage::from_fn(width, height, |x, y| {
+            let u = x as f32 / width as f32;
+            let v = y as f32 / height as f32;
+            image::Rgba([u * 0.8 + 0.1, v, 0.6 - u * 0.4, 1.0])
+        });
+        let strip =
+            image::Rgba32FImage::from_fn(width * 3, height, |x, y| *tile.get_pixel(x % width, y));
+        let quantize = |image_buffer: &image::Rgba32FImage, border| {
+            crate::bgf::Bitmap::quantize(
+                image_buffer,
+                &crate::bgf::BitmapImageOptions {
+                    dither: DitherOptions::Custom,
+                    diffusion_kernel: Some(kernel.clone()),
+                    border,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        let wrapped = quantize(&tile, BorderPolicy::Wrap);
+        let repeated = quantize(&strip, BorderPolicy::Drop);
+
+        for (y, row) in wrapped.chunks(width as usize).enumerate() {
+            let start = (y * 3 + 1) * width as usize;
+
+            assert_eq!(row, &repeated[start..start + width as usize], "row {y}");
+        }
+    }
+
+    // Expected indices for a small image through every dither, kept in
+    // tests/fixtures/dither. Run with BGFTOOL_BLESS=1 to write them again
+    // after an intended change.
+    const GOLDEN_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/dither");
+
+    // A color gradient that fades out towards the left, with a fully
+    // transparent column along that edge.
+    fn golden_image() -> image::Rgba32FImage {
+        let (width, height) = (16, 12);
+
+        image::Rgba32FImage::from_fn(width, height, |x, y| {
+            let u = x as f32 / (width - 1) as f32;
+            let v = y as f32 / (height - 1) as f32;
+            image::Rgba([u, v, 1.0 - (u + v) / 2.0, (u * 4.0).min(1.0)])
         })
-        .collect()
+    }
+
+    fn golden_kernel() -> crate::conf::DiffusionKernel {
+        crate::conf::DiffusionKernel {
+            divisor: 8.0,
+            weights: [
+                (2.0, (1, 0)),
+                (1.0, (2, 0)),
+                (2.0, (-1, 1)),
+                (2.0, (0, 1)),
+                (1.0, (1, 2)),
+            ]
+            .into_iter()
+            .map(|(weight, offset)| crate::conf::DiffusionKernelEntry { weight, offset })
+            .collect(),
+        }
+    }
+
+    fn check_golden(name: &str, indices: &[u8], width: usize) {
+        let path = std::path::Path::new(GOLDEN_DIR).join(format!("{name}.txt"));
+        let text = indices
+            .chunks(width)
+            .map(|row| {
+                row.iter()
+                    .map(|index| format!("{index:3}"))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+            + "\n";
+
+        if std::env::var_os("BGFTOOL_BLESS").is_some() {
+            std::fs::create_dir_all(GOLDEN_DIR).unwrap();
+            std::fs::write(&path, text).unwrap();
+            return;
+        }
+
+        let expected = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Couldn't read {}: {e}", path.display()));
+
+        assert!(
+            expected == text,
+            "{name} doesn't match {}.\nExpected:\n{expected}\nGot:\n{text}",
+            path.display()
+        );
+    }
+
+    #[test]
+    fn golden_dithers() {
+        let image_buffer = golden_image();
+
+        for dither in <DitherOptions as clap::ValueEnum>::value_variants() {
+            let name = clap::ValueEnum::to_possible_value(dither).unwrap();
+            let options = crate::bgf::BitmapImageOptions {
+                dither: *dither,
+                transparency_clip: 0.5,
+                diffusion_kernel: Some(golden_kernel()),
+                ..Default::default()
+            };
+            let error_diffusion = matches!(
+                dither,
+                DitherOptions::FloydSteinberg
+                    | DitherOptions::JavisJudiceNinke
+                    | DitherOptions::Stucki
+                    | DitherOptions::Atkinson
+                    | DitherOptions::Burkes
+                    | DitherOptions::Sierra
+                    | DitherOptions::TwoRowSierra
+                    | DitherOptions::SierraLite
+                    | DitherOptions::Custom
+            );
+
+            // Borders only change anything for error diffusion.
+            if !error_diffusion {
+                let indices = crate::bgf::Bitmap::quantize(&image_buffer, &options).unwrap();
+                check_golden(name.get_name(), &indices, image_buffer.width() as usize);
+                continue;
+            }
+
+            for border in <BorderPolicy as clap::ValueEnum>::value_variants() {
+                let border_name = clap::ValueEnum::to_possible_value(border).unwrap();
+                let options = crate::bgf::BitmapImageOptions {
+                    border: *border,
+                    ..options.clone()
+                };
+                let indices = crate::bgf::Bitmap::quantize(&image_buffer, &options).unwrap();
+                check_golden(
+                    &format!("{}-{}", name.get_name(), border_name.get_name()),
+                    &indices,
+                    image_buffer.width() as usize,
+                );
+            }
+        }
+    }
 }
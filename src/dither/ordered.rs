@@ -0,0 +1,166 @@
+pub struct BayerMatrixGenerator {
+    size: usize,
+    values: Vec<f32>,
+}
+
+impl BayerMatrixGenerator {
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1).next_power_of_two();
+        let mut matrix = vec![0u32];
+        let mut matrix_size = 1;
+
+        // Grow the matrix by recursively tiling the previous level:
+        // M(2n) = [[4M, 4M + 2], [4M + 3, 4M + 1]]
+        while matrix_size < size {
+            let next_size = matrix_size * 2;
+            let mut next = vec![0u32; next_size * next_size];
+
+            for y in 0..next_size {
+                for x in 0..next_size {
+                    let value = matrix[(y % matrix_size) * matrix_size + (x % matrix_size)] * 4;
+                    let quadrant = match (x / matrix_size, y / matrix_size) {
+                        (0, 0) => 0,
+                        (1, 0) => 2,
+                        (0, _) => 3,
+                        _ => 1,
+                    };
+
+                    next[y * next_size + x] = value + quadrant;
+                }
+            }
+
+            matrix = next;
+            matrix_size = next_size;
+        }
+
+        let cell_count = (size * size) as f32;
+        let values = matrix
+            .into_iter()
+            .map(|v| (v as f32 + 0.5) / cell_count)
+            .collect();
+
+        Self { size, values }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        self.values[(y % self.size) * self.size + (x % self.size)]
+    }
+}
+
+// Palette aware ordered dithering based on Joel Yliluoma's arbitrary-palette
+// positional dithering algorithm (algorithm 2).
+// https://bisqwit.iki.fi/story/howto/dither/jy/
+
+pub const YLILUOMA_PLAN_SIZE: usize = 16;
+
+pub struct YliluomaMixer {
+    threshold: BayerMatrixGenerator,
+}
+
+impl YliluomaMixer {
+    pub fn new() -> Self {
+        Self {
+            threshold: BayerMatrixGenerator::new(4),
+        }
+    }
+
+    pub fn threshold(&self, x: usize, y: usize) -> f32 {
+        self.threshold.get(x, y)
+    }
+
+    // Colors are in the 0.0 to 255.0 range, in whatever space the plan should
+    // be mixed in.
+    pub fn mixing_plan(
+        &self,
+        target: &[f32; 3],
+        palette_colors: &[[f32; 3]],
+        transparent_index: usize,
+    ) -> [usize; YLILUOMA_PLAN_SIZE] {
+        let mut plan = [0usize; YLILUOMA_PLAN_SIZE];
+        let mut so_far = [0.0f32; 3];
+        let mut proportion_total = 0;
+
+        while proportion_total < YLILUOMA_PLAN_SIZE {
+            let mut chosen_amount = 1;
+            let mut chosen = 0;
+            let mut least_penalty = f32::MAX;
+            let max_test_count = proportion_total.max(1);
+
+            for (index, candidate) in palette_colors.iter().enumerate() {
+                if index == transparent_index {
+                    continue;
+                }
+
+                let mut sum = so_far;
+                let mut add = *candidate;
+                let mut p = 1;
+
+                while p <= max_test_count {
+                    sum.iter_mut().zip(add).for_each(|(s, a)| *s += a);
+                    add.iter_mut().for_each(|a| *a += *a);
+
+                    let t = (proportion_total + p) as f32;
+                    let test = [sum[0] / t, sum[1] / t, sum[2] / t];
+                    let penalty = color_compare(target, &test);
+
+                    if penalty < least_penalty {
+                        least_penalty = penalty;
+                        chosen = index;
+                        chosen_amount = p;
+                    }
+
+                    p *= 2;
+                }
+            }
+
+            let chosen_color = palette_colors[chosen];
+
+            for _ in 0..chosen_amount {
+                if proportion_total >= YLILUOMA_PLAN_SIZE {
+                    break;
+                }
+
+                plan[proportion_total] = chosen;
+                proportion_total += 1;
+            }
+
+            so_far
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, s)| *s += chosen_color[i] * chosen_amount as f32);
+        }
+
+        plan.sort_by(|a, b| {
+            let a = luma(&palette_colors[*a]);
+            let b = luma(&palette_colors[*b]);
+
+            a.partial_cmp(&b).unwrap()
+        });
+
+        plan
+    }
+}
+
+impl Default for YliluomaMixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[inline(always)]
+fn luma(color: &[f32; 3]) -> f32 {
+    color[0] * 0.299 + color[1] * 0.587 + color[2] * 0.114
+}
+
+#[inline(always)]
+fn color_compare(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    let luma_a = (a[0] * 0.299 + a[1] * 0.587 + a[2] * 0.114) / 255.0;
+    let luma_b = (b[0] * 0.299 + b[1] * 0.587 + b[2] * 0.114) / 255.0;
+    let luma_diff = luma_a - luma_b;
+    let diff_r = (a[0] - b[0]) / 255.0;
+    let diff_g = (a[1] - b[1]) / 255.0;
+    let diff_b = (a[2] - b[2]) / 255.0;
+
+    (diff_r * diff_r * 0.299 + diff_g * diff_g * 0.587 + diff_b * diff_b * 0.114) * 0.75
+        + luma_diff * luma_diff
+}
@@ -0,0 +1,370 @@
+use color_eyre::eyre::Result;
+
+// Quality metrics for a dithered image against its source. Both images are
+// composited over black first, so pixels that were cut out (or kept) by
+// mistake count against the result too.
+
+// SSIM is measured over windows of this size, stepping half a window at a time.
+const SSIM_WINDOW: usize = 8;
+const SSIM_C1: f64 = 0.01 * 0.01;
+const SSIM_C2: f64 = 0.03 * 0.03;
+
+// Dithering trades per pixel accuracy for the average color over an area, so
+// raw per pixel metrics always favor not dithering. Blurring both images by
+// about a pixel first gives something closer to what is seen.
+const PERCEIVED_SIGMA: f64 = 1.0;
+
+// Identical images have no noise at all, which would be an infinite PSNR.
+// That can't be written to JSON, so it's capped well above anything a dither
+// gets to.
+const MAX_PSNR: f64 = 100.0;
+
+// Error at or above this shows up as the hottest color in the heatmap.
+const HEATMAP_MAX_DELTA_E: f64 = 10.0;
+const HEATMAP_COLORS: [[f64; 3]; 6] = [
+    [0.0, 0.0, 0.0],
+    [0.0, 0.0, 255.0],
+    [0.0, 255.0, 255.0],
+    [0.0, 255.0, 0.0],
+    [255.0, 255.0, 0.0],
+    [255.0, 0.0, 0.0],
+];
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Metrics {
+    pub mean_delta_e: f64,
+    pub max_delta_e: f64,
+    pub psnr: f64,
+    pub ssim: f64,
+    pub perceived_delta_e: f64,
+    pub palette_colors: usize,
+}
+
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Metric {
+    DeltaE,
+    Psnr,
+    Ssim,
+    #[default]
+    PerceivedDeltaE,
+}
+
+impl Metric {
+    // Higher is always better.
+    pub fn score(&self, metrics: &Metrics) -> f64 {
+        match self {
+            Self::DeltaE => -metrics.mean_delta_e,
+            Self::Psnr => metrics.psnr,
+            Self::Ssim => metrics.ssim,
+            Self::PerceivedDeltaE => -metrics.perceived_delta_e,
+        }
+    }
+}
+
+pub fn measure(
+    image_buffer: &image::Rgba32FImage,
+    indices: &[u8],
+    palette: &crate::bgf::Palette,
+) -> Metrics {
+    let (transparent_index, _) = palette.transparent_color();
+    let width = image_buffer.width() as usize;
+    let height = image_buffer.height() as usize;
+    let (source, output) = composite(image_buffer, indices, palette);
+    let delta_es = source
+        .iter()
+        .zip(&output)
+        .map(|(a, b)| delta_e(a, b))
+        .collect::<Vec<_>>();
+    let perceived_delta_e = blur(&source, (width, height))
+        .iter()
+        .zip(&blur(&output, (width, height)))
+        .map(|(a, b)| delta_e(a, b))
+        .sum::<f64>();
+    let pixel_count = delta_es.len().max(1) as f64;
+
+    let mse = source
+        .iter()
+        .zip(&output)
+        .map(|(a, b)| (0..3).map(|i| (a[i] - b[i]).powi(2)).sum::<f64>())
+        .sum::<f64>()
+        / (pixel_count * 3.0);
+
+    let mut used = [false; 256];
+    indices
+        .iter()
+        .for_each(|index| used[*index as usize] = true);
+    used[transparent_index] = false;
+
+    Metrics {
+        mean_delta_e: delta_es.iter().sum::<f64>() / pixel_count,
+        max_delta_e: delta_es.iter().copied().fold(0.0, f64::max),
+        psnr: if mse > 0.0 {
+            (-10.0 * mse.log10()).min(MAX_PSNR)
+        } else {
+            MAX_PSNR
+        },
+        ssim: ssim(
+            &source.iter().map(luma).collect::<Vec<_>>(),
+            &output.iter().map(luma).collect::<Vec<_>>(),
+            (width, height),
+        ),
+        perceived_delta_e: perceived_delta_e / pixel_count,
+        palette_colors: used.iter().filter(|v| **v).count(),
+    }
+}
+
+// False color image of the perceived error, going from black (none) through
+// blue, cyan, green and yellow to red. Uses the blurred error, otherwise any
+// dither just shows up as noise.
+pub fn error_heatmap(
+    image_buffer: &image::Rgba32FImage,
+    indices: &[u8],
+    palette: &crate::bgf::Palette,
+) -> image::RgbImage {
+    let size = (
+        image_buffer.width() as usize,
+        image_buffer.height() as usize,
+    );
+    let (source, output) = composite(image_buffer, indices, palette);
+    let (source, output) = (blur(&source, size), blur(&output, size));
+    let mut heatmap = image::RgbImage::new(image_buffer.width(), image_buffer.height());
+
+    heatmap
+        .pixels_mut()
+        .zip(source.iter().zip(&output))
+        .for_each(|(pixel, (a, b))| {
+            let t = (delta_e(a, b) / HEATMAP_MAX_DELTA_E).clamp(0.0, 1.0)
+                * (HEATMAP_COLORS.len() - 1) as f64;
+            let i = (t as usize).min(HEATMAP_COLORS.len() - 2);
+            let fract = t - i as f64;
+            let (from, to) = (HEATMAP_COLORS[i], HEATMAP_COLORS[i + 1]);
+
+            *pixel = image::Rgb([0, 1, 2].map(|c| (from[c] + (to[c] - from[c]) * fract) as u8));
+        });
+
+    heatmap
+}
+
+// The source and the dithered result as sRGB colors, both composited over
+// black.
+fn composite(
+    image_buffer: &image::Rgba32FImage,
+    indices: &[u8],
+    palette: &crate::bgf::Palette,
+) -> (Vec<[f64; 3]>, Vec<[f64; 3]>) {
+    let (transparent_index, _) = palette.transparent_color();
+    let source = image_buffer
+        .pixels()
+        .map(|pixel| {
+            let alpha = pixel[3].clamp(0.0, 1.0) as f64;
+            [0, 1, 2].map(|i| pixel[i].clamp(0.0, 1.0) as f64 * alpha)
+        })
+        .collect::<Vec<_>>();
+    let output = indices
+        .iter()
+        .map(|index| {
+            if *index as usize == transparent_index {
+                [0.0; 3]
+            } else {
+                palette.values()[*index as usize]
+                    .0
+                    .map(|v| v as f64 / 255.0)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    (source, output)
+}
+
+// Try every built in dither, and keep the one that scores best.
+pub fn best_dither(
+    image_buffer: &image::Rgba32FImage,
+    options: &crate::bgf::BitmapImageOptions,
+    metric: Metric,
+) -> Result<(super::DitherOptions, Metrics)> {
+    let palette = crate::bgf::Palette::new();
+    let mut best: Option<(super::DitherOptions, Metrics)> = None;
+
+    for dither in <super::DitherOptions as clap::ValueEnum>::value_variants() {
+        match dither {
+            super::DitherOptions::Auto => continue,
+            super::DitherOptions::Custom if options.diffusion_kernel.is_none() => continue,
+            _ => {}
+        }
+
+        let candidate_options = crate::bgf::BitmapImageOptions {
+            dither: *dither,
+            ditherer: None,
+            ..options.clone()
+        };
+        let indices = crate::bgf::Bitmap::quantize(image_buffer, &candidate_options)?;
+        let metrics = measure(image_buffer, &indices, &palette);
+
+        if best
+            .as_ref()
+            .is_none_or(|(_, best)| metric.score(&metrics) > metric.score(best))
+        {
+            best = Some((*dither, metrics));
+        }
+    }
+
+    Ok(best.unwrap())
+}
+
+#[inline(always)]
+fn luma(color: &[f64; 3]) -> f64 {
+    color[0] * 0.299 + color[1] * 0.587 + color[2] * 0.114
+}
+
+#[inline(always)]
+fn delta_e(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    let (a, b) = (lab(a), lab(b));
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+// Separable Gaussian blur, clamping at the edges. Colors are blurred in linear
+// light, the same way the eye averages them.
+fn blur(colors: &[[f64; 3]], size: (usize, usize)) -> Vec<[f64; 3]> {
+    let (width, height) = size;
+    let radius = (PERCEIVED_SIGMA * 3.0).ceil() as isize;
+    let kernel = (-radius..=radius)
+        .map(|i| (-(i * i) as f64 / (2.0 * PERCEIVED_SIGMA * PERCEIVED_SIGMA)).exp())
+        .collect::<Vec<_>>();
+    let kernel_sum = kernel.iter().sum::<f64>();
+    let pass = |colors: &[[f64; 3]], step: (isize, isize)| {
+        (0..width * height)
+            .map(|index| {
+                let (x, y) = ((index % width) as isize, (index / width) as isize);
+                let mut sum = [0.0; 3];
+
+                for (i, weight) in (-radius..=radius).zip(&kernel) {
+                    let sx = (x + i * step.0).clamp(0, width as isize - 1) as usize;
+                    let sy = (y + i * step.1).clamp(0, height as isize - 1) as usize;
+                    let color = colors[sy * width + sx];
+                    (0..3).for_each(|c| sum[c] += color[c] * weight);
+                }
+
+                sum.map(|v| v / kernel_sum)
+            })
+            .collect::<Vec<_>>()
+    };
+    let linear = colors
+        .iter()
+        .map(|color| color.map(|v| super::srgb_to_linear(v as f32) as f64))
+        .collect::<Vec<_>>();
+
+    pass(&pass(&linear, (1, 0)), (0, 1))
+        .iter()
+        .map(|color| color.map(|v| super::linear_to_srgb(v as f32) as f64))
+        .collect()
+}
+
+// CIE L*a*b* from sRGB, with a D65 white point.
+fn lab(color: &[f64; 3]) -> [f64; 3] {
+    let [r, g, b] = color.map(|v| super::srgb_to_linear(v as f32) as f64);
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f64| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+fn ssim(a: &[f64], b: &[f64], size: (usize, usize)) -> f64 {
+    let (width, height) = size;
+    let window = (SSIM_WINDOW.min(width), SSIM_WINDOW.min(height));
+    let step = ((window.0 / 2).max(1), (window.1 / 2).max(1));
+    let mut total = 0.0;
+    let mut count = 0;
+
+    for top in (0..=height.saturating_sub(window.1)).step_by(step.1) {
+        for left in (0..=width.saturating_sub(window.0)).step_by(step.0) {
+            let samples = (top..top + window.1)
+                .flat_map(|y| (left..left + window.0).map(move |x| y * width + x))
+                .map(|index| (a[index], b[index]))
+                .collect::<Vec<_>>();
+            let n = samples.len() as f64;
+
+            if n == 0.0 {
+                continue;
+            }
+
+            let mean_a = samples.iter().map(|s| s.0).sum::<f64>() / n;
+            let mean_b = samples.iter().map(|s| s.1).sum::<f64>() / n;
+            let (mut var_a, mut var_b, mut covariance) = (0.0, 0.0, 0.0);
+
+            for (sa, sb) in &samples {
+                var_a += (sa - mean_a).powi(2);
+                var_b += (sb - mean_b).powi(2);
+                covariance += (sa - mean_a) * (sb - mean_b);
+            }
+
+            let (var_a, var_b, covariance) = (var_a / n, var_b / n, covariance / n);
+
+            total += ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+                / ((mean_a.powi(2) + mean_b.powi(2) + SSIM_C1) * (var_a + var_b + SSIM_C2));
+            count += 1;
+        }
+    }
+
+    if count == 0 {
+        1.0
+    } else {
+        total / count as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn identical_images_have_finite_psnr() {
+        let palette = crate::bgf::Palette::new();
+        let image_buffer = image::Rgba32FImage::from_fn(8, 8, |x, y| {
+            let color = palette.values()[(y * 8 + x) as usize];
+            image::Rgba([color[0], color[1], color[2], 255].map(|v| v as f32 / 255.0))
+        });
+        let options = crate::bgf::BitmapImageOptions::default();
+        let indices = crate::bgf::Bitmap::quantize(&image_buffer, &options).unwrap();
+        let metrics = super::measure(&image_buffer, &indices, &palette);
+
+        assert_eq!(metrics.psnr, super::MAX_PSNR);
+        assert!(serde_json::to_value(metrics).unwrap()["psnr"].is_f64());
+
+        // Every dither leaves exact colors alone, so they all tie at the cap
+        // and the first is kept.
+        let (_, best) = super::best_dither(&image_buffer, &options, super::Metric::Psnr).unwrap();
+        assert_eq!(best.psnr, super::MAX_PSNR);
+    }
+
+    #[test]
+    fn psnr_orders_by_error() {
+        let palette = crate::bgf::Palette::new();
+        let image_buffer = image::Rgba32FImage::from_pixel(4, 4, image::Rgba([0.5, 0.4, 0.3, 1.0]));
+        let options = crate::bgf::BitmapImageOptions::default();
+        let closest = crate::bgf::Bitmap::quantize(&image_buffer, &options).unwrap();
+        let black = palette.find_closest(&image::Rgb([0, 0, 0])).0 as u8;
+        let closest = super::measure(&image_buffer, &closest, &palette);
+        let black = super::measure(&image_buffer, &[black; 16], &palette);
+
+        assert!(closest.psnr.is_finite() && black.psnr.is_finite());
+        assert!(super::Metric::Psnr.score(&closest) > super::Metric::Psnr.score(&black));
+    }
+}
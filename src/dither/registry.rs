@@ -0,0 +1,136 @@
+use color_eyre::eyre::{self, Result};
+
+// Dithers that can be picked by name. The built in algorithms are always
+// available under their command line names, and other crates can add their
+// own with `register`.
+
+pub type DithererFactory = std::sync::Arc<
+    dyn Fn(&crate::bgf::BitmapImageOptions, (u32, u32)) -> Result<Box<dyn super::Ditherer>>
+        + Send
+        + Sync,
+>;
+
+static REGISTRY: std::sync::LazyLock<
+    std::sync::RwLock<std::collections::BTreeMap<String, DithererFactory>>,
+> = std::sync::LazyLock::new(|| {
+    let mut registry = std::collections::BTreeMap::new();
+
+    for algorithm in <super::DitherOptions as clap::ValueEnum>::value_variants() {
+        // Auto picks between the others, so it isn't a dither on its own.
+        if *algorithm == super::DitherOptions::Auto {
+            continue;
+        }
+
+        let Some(name) = clap::ValueEnum::to_possible_value(algorithm) else {
+            continue;
+        };
+        let algorithm = *algorithm;
+        let factory: DithererFactory = std::sync::Arc::new(move |options, size| {
+            let options = crate::bgf::BitmapImageOptions {
+                dither: algorithm,
+                ..options.clone()
+            };
+            let generator = super::DitherGenerator::from_options(&options, size)?;
+
+            Ok(Box::new(generator) as Box<dyn super::Ditherer>)
+        });
+
+        registry.insert(name.get_name().to_string(), factory);
+    }
+
+    std::sync::RwLock::new(registry)
+});
+
+// Add a dither under `name`, replacing anything already registered with it.
+pub fn register<F>(name: &str, factory: F)
+where
+    F: Fn(&crate::bgf::BitmapImageOptions, (u32, u32)) -> Result<Box<dyn super::Ditherer>>
+        + Send
+        + Sync
+        + 'static,
+{
+    REGISTRY
+        .write()
+        .unwrap()
+        .insert(name.to_string(), std::sync::Arc::new(factory));
+}
+
+pub fn create(
+    name: &str,
+    options: &crate::bgf::BitmapImageOptions,
+    size: (u32, u32),
+) -> Result<Box<dyn super::Ditherer>> {
+    // The lock is let go of before calling the factory, so it can register
+    // dithers of its own.
+    let factory = REGISTRY.read().unwrap().get(name).cloned();
+    let factory = factory.ok_or_else(|| {
+        eyre::eyre!(
+            "Unknown ditherer \"{}\". Available ditherers: {}",
+            name,
+            names().join(", ")
+        )
+    })?;
+
+    factory(options, size)
+}
+
+pub fn names() -> Vec<String> {
+    REGISTRY.read().unwrap().keys().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    // Fills everything with one color, ignoring any locks.
+    struct Fill(u8);
+
+    impl super::super::Ditherer for Fill {
+        fn dither(
+            &self,
+            image_buffer: &image::Rgba32FImage,
+            _: &crate::bgf::BitmapImageOptions,
+            _: &crate::bgf::Palette,
+        ) -> Vec<u8> {
+            vec![self.0; image_buffer.pixels().len()]
+        }
+    }
+
+    #[test]
+    fn factory_can_register() {
+        super::register("test-outer", |_, _| {
+            super::register("test-inner", |_, _| Ok(Box::new(Fill(1))));
+            Ok(Box::new(Fill(0)))
+        });
+
+        let options = crate::bgf::BitmapImageOptions::default();
+        assert!(super::create("test-outer", &options, (1, 1)).is_ok());
+        assert!(super::create("test-inner", &options, (1, 1)).is_ok());
+    }
+
+    #[test]
+    fn registered_ditherers_keep_protected_pixels() {
+        super::register("test-fill", |_, _| Ok(Box::new(Fill(0))));
+
+        let palette = crate::bgf::Palette::new();
+        let color = palette.values()[100];
+        // The palette repeats some colors, and the first one is used.
+        let exact = palette.values().iter().position(|c| *c == color).unwrap() as u8;
+        let [r, g, b] = color.0.map(|v| v as f32 / 255.0);
+        let image_buffer = image::Rgba32FImage::from_fn(2, 1, |x, _| match x {
+            0 => image::Rgba([r, g, b, 1.0]),
+            _ => image::Rgba([0.5, 0.25, 0.75, 1.0]),
+        });
+        let mut options = crate::bgf::BitmapImageOptions {
+            ditherer: Some("test-fill".to_string()),
+            ..Default::default()
+        };
+
+        let indices = crate::bgf::Bitmap::quantize(&image_buffer, &options).unwrap();
+        assert_eq!(indices, [exact, 0]);
+
+        // Pixels outside of the dither mask get their closest color instead.
+        options.dither_mask = Some(vec![true, false]);
+        let indices = crate::bgf::Bitmap::quantize(&image_buffer, &options).unwrap();
+        let closest = palette.find_closest(&image::Rgb([127, 63, 191])).0 as u8;
+        assert_eq!(indices, [exact, closest]);
+    }
+}
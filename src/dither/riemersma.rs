@@ -0,0 +1,82 @@
+// Riemersma dithering based on
+// https://www.compuphase.com/riemer.htm
+
+// Ratio between the weight of the newest and oldest error in the history.
+const WEIGHT_RATIO: f32 = 16.0;
+
+pub struct RiemersmaGenerator {
+    weights: Vec<f32>,
+}
+
+impl RiemersmaGenerator {
+    pub fn new(history_len: usize) -> Self {
+        let history_len = history_len.max(1);
+        let multiplier = if history_len > 1 {
+            (WEIGHT_RATIO.ln() / (history_len - 1) as f32).exp()
+        } else {
+            WEIGHT_RATIO
+        };
+        let mut weight = 1.0;
+
+        // Oldest error first, so the newest error gets the largest weight.
+        let weights = (0..history_len)
+            .map(|_| {
+                let value = weight / WEIGHT_RATIO;
+                weight *= multiplier;
+                value
+            })
+            .collect();
+
+        Self { weights }
+    }
+
+    pub fn weights(&self) -> &[f32] {
+        &self.weights
+    }
+}
+
+// Walk a Hilbert curve covering the smallest power of two square that fits
+// the image, skipping any points that fall outside of it.
+pub fn hilbert_curve(width: usize, height: usize) -> Vec<(usize, usize)> {
+    let order = width.max(height).max(1).next_power_of_two();
+    let mut curve = Vec::with_capacity(width * height);
+
+    for d in 0..order * order {
+        let (x, y) = hilbert_point(order, d);
+
+        if x < width && y < height {
+            curve.push((x, y));
+        }
+    }
+
+    curve
+}
+
+#[inline(always)]
+fn hilbert_point(order: usize, d: usize) -> (usize, usize) {
+    let mut t = d;
+    let (mut x, mut y) = (0, 0);
+    let mut s = 1;
+
+    while s < order {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+
+        // Rotate the quadrant
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+
+    (x, y)
+}
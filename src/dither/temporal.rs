@@ -0,0 +1,96 @@
+// Helpers for dithering the frames of an index group so that areas that don't
+// change between frames don't shimmer when the animation plays back.
+
+pub struct Frame<'a> {
+    pub image: &'a image::Rgba32FImage,
+    pub offset: (i32, i32),
+    pub indices: &'a [u8],
+}
+
+impl Frame<'_> {
+    // Look up the source pixel and palette index at a position on the canvas.
+    fn sample(&self, position: (i64, i64)) -> Option<(&image::Rgba<f32>, u8)> {
+        let x = position.0 - self.offset.0 as i64;
+        let y = position.1 - self.offset.1 as i64;
+
+        if x < 0 || y < 0 || x >= self.image.width() as i64 || y >= self.image.height() as i64 {
+            return None;
+        }
+
+        let index = y as usize * self.image.width() as usize + x as usize;
+
+        Some((
+            self.image.get_pixel(x as u32, y as u32),
+            self.indices[index],
+        ))
+    }
+}
+
+// Reuse the previous frame's decision for every pixel whose source color is
+// unchanged at the same spot on the canvas.
+pub fn locked_indices(
+    image_buffer: &image::Rgba32FImage,
+    offset: (i32, i32),
+    previous: &Frame,
+) -> Vec<Option<u8>> {
+    image_buffer
+        .enumerate_pixels()
+        .map(|(x, y, pixel)| {
+            let position = (x as i64 + offset.0 as i64, y as i64 + offset.1 as i64);
+
+            previous
+                .sample(position)
+                .filter(|(previous_pixel, _)| same_source(pixel, previous_pixel))
+                .map(|(_, color_index)| color_index)
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Serialize)]
+pub struct Flicker {
+    pub compared: usize,
+    pub source_changed: usize,
+    pub output_changed: usize,
+    pub static_changed: usize,
+}
+
+impl Flicker {
+    pub fn add(&mut self, previous: &Frame, current: &Frame) {
+        let width = current.image.width() as usize;
+
+        for (x, y, pixel) in current.image.enumerate_pixels() {
+            let position = (
+                x as i64 + current.offset.0 as i64,
+                y as i64 + current.offset.1 as i64,
+            );
+            let Some((previous_pixel, previous_index)) = previous.sample(position) else {
+                continue;
+            };
+            let source_changed = !same_source(pixel, previous_pixel);
+            let output_changed = current.indices[y as usize * width + x as usize] != previous_index;
+
+            self.compared += 1;
+            self.source_changed += source_changed as usize;
+            self.output_changed += output_changed as usize;
+            self.static_changed += (output_changed && !source_changed) as usize;
+        }
+    }
+
+    // Fraction of the pixels that didn't change in the source, but did in the
+    // dithered output.
+    pub fn static_flicker(&self) -> f32 {
+        let static_count = self.compared - self.source_changed;
+
+        if static_count == 0 {
+            0.0
+        } else {
+            self.static_changed as f32 / static_count as f32
+        }
+    }
+}
+
+#[inline(always)]
+fn same_source(a: &image::Rgba<f32>, b: &image::Rgba<f32>) -> bool {
+    // Fully transparent pixels are the same no matter what color they hold.
+    a == b || (a[3] == 0.0 && b[3] == 0.0)
+}
@@ -0,0 +1,194 @@
+use rand::prelude::*;
+
+// Blue noise texture generation based on Robert Ulichney's void-and-cluster
+// method.
+// https://cv.ulichney.com/papers/1993-void-cluster.pdf
+
+const SIGMA: f32 = 1.5;
+const INITIAL_DENSITY: f32 = 0.1;
+
+pub struct VoidAndClusterGenerator<const N: usize> {
+    size: usize,
+    offset: (usize, usize),
+    texture: std::sync::Arc<Vec<f32>>,
+}
+
+impl<const N: usize> VoidAndClusterGenerator<N> {
+    pub fn new(size: usize, offset: (usize, usize)) -> Self {
+        static CACHED_TEXTURES: std::sync::LazyLock<
+            std::sync::Mutex<std::collections::HashMap<usize, std::sync::Arc<Vec<f32>>>>,
+        > = std::sync::LazyLock::new(Default::default);
+
+        assert!(
+            (super::MIN_BLUE_NOISE_SIZE..=super::MAX_BLUE_NOISE_SIZE).contains(&size),
+            "Blue noise size {size} is out of range."
+        );
+
+        let cached = CACHED_TEXTURES.lock().unwrap().get(&size).cloned();
+        // Generating takes a while, so it's done without holding the lock to
+        // let bitmaps using other textures carry on.
+        let texture = cached.unwrap_or_else(|| {
+            let texture = std::sync::Arc::new(generate_texture(size));
+            CACHED_TEXTURES
+                .lock()
+                .unwrap()
+                .entry(size)
+                .or_insert(texture)
+                .clone()
+        });
+
+        Self {
+            size,
+            offset,
+            texture,
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> [f32; N] {
+        let mut next_value = [0.0; N];
+
+        next_value.iter_mut().enumerate().for_each(|(i, v)| {
+            // Each channel samples the texture at a different shift so the
+            // channels are not correlated with each other.
+            let shift_x = (i & 1) * self.size / 2;
+            let shift_y = ((i >> 1) & 1) * self.size / 2;
+            let sample_x = (x + self.offset.0 + shift_x) % self.size;
+            let sample_y = (y + self.offset.1 + shift_y) % self.size;
+            let sample = self.texture[sample_y * self.size + sample_x];
+
+            if i != 3 {
+                // Same range as the R2 generator, -1.0 to 1.0 for the RGB
+                // channels and 0.0 to 1.0 for alpha.
+                *v = sample * 2.0 - 1.0
+            } else {
+                *v = sample
+            }
+        });
+
+        next_value
+    }
+}
+
+fn generate_texture(size: usize) -> Vec<f32> {
+    let pixel_count = size * size;
+    let kernel = gaussian_kernel(size);
+    let mut rng = rand_pcg::Pcg64Mcg::seed_from_u64(0);
+
+    // Start with a random binary pattern, then shuffle points from the
+    // tightest clusters into the largest voids until it settles.
+    let initial_count = ((pixel_count as f32 * INITIAL_DENSITY) as usize).max(1);
+    let mut pattern = vec![false; pixel_count];
+    let mut placed = 0;
+
+    while placed < initial_count {
+        let index = rng.random_range(0..pixel_count);
+
+        if !pattern[index] {
+            pattern[index] = true;
+            placed += 1;
+        }
+    }
+
+    let mut energy = vec![0.0f32; pixel_count];
+
+    for (index, _) in pattern.iter().enumerate().filter(|(_, v)| **v) {
+        update_energy(&mut energy, &kernel, size, index, 1.0);
+    }
+
+    loop {
+        let cluster = tightest_cluster(&pattern, &energy);
+        pattern[cluster] = false;
+        update_energy(&mut energy, &kernel, size, cluster, -1.0);
+
+        let void = largest_void(&pattern, &energy);
+
+        if void == cluster {
+            pattern[cluster] = true;
+            update_energy(&mut energy, &kernel, size, cluster, 1.0);
+            break;
+        }
+
+        pattern[void] = true;
+        update_energy(&mut energy, &kernel, size, void, 1.0);
+    }
+
+    let mut ranks = vec![0usize; pixel_count];
+
+    // Phase 1: remove the tightest clusters from the prototype pattern, giving
+    // them descending ranks.
+    {
+        let mut pattern = pattern.clone();
+        let mut energy = energy.clone();
+
+        for rank in (0..initial_count).rev() {
+            let cluster = tightest_cluster(&pattern, &energy);
+            pattern[cluster] = false;
+            update_energy(&mut energy, &kernel, size, cluster, -1.0);
+            ranks[cluster] = rank;
+        }
+    }
+
+    // Phase 2 and 3: fill the largest voids until every pixel has a rank.
+    for rank in initial_count..pixel_count {
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        update_energy(&mut energy, &kernel, size, void, 1.0);
+        ranks[void] = rank;
+    }
+
+    ranks
+        .into_iter()
+        .map(|rank| (rank as f32 + 0.5) / pixel_count as f32)
+        .collect()
+}
+
+fn gaussian_kernel(size: usize) -> Vec<f32> {
+    let mut kernel = vec![0.0f32; size * size];
+
+    for y in 0..size {
+        for x in 0..size {
+            // The texture tiles, so distances wrap around the edges.
+            let dx = x.min(size - x) as f32;
+            let dy = y.min(size - y) as f32;
+            kernel[y * size + x] = (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp();
+        }
+    }
+
+    kernel
+}
+
+#[inline(always)]
+fn update_energy(energy: &mut [f32], kernel: &[f32], size: usize, index: usize, sign: f32) {
+    let (px, py) = (index % size, index / size);
+
+    for y in 0..size {
+        let ky = (y + size - py) % size;
+
+        for x in 0..size {
+            let kx = (x + size - px) % size;
+            energy[y * size + x] += sign * kernel[ky * size + kx];
+        }
+    }
+}
+
+fn tightest_cluster(pattern: &[bool], energy: &[f32]) -> usize {
+    pattern
+        .iter()
+        .zip(energy)
+        .enumerate()
+        .filter(|(_, (p, _))| **p)
+        .max_by(|(_, (_, a)), (_, (_, b))| a.partial_cmp(b).unwrap())
+        .map(|(index, _)| index)
+        .unwrap()
+}
+
+fn largest_void(pattern: &[bool], energy: &[f32]) -> usize {
+    pattern
+        .iter()
+        .zip(energy)
+        .enumerate()
+        .filter(|(_, (p, _))| !**p)
+        .min_by(|(_, (_, a)), (_, (_, b))| a.partial_cmp(b).unwrap())
+        .map(|(index, _)| index)
+        .unwrap()
+}
@@ -0,0 +1,199 @@
+use std::sync::atomic::{AtomicU8, AtomicU32, AtomicUsize, Ordering};
+
+// Error diffusion spread over multiple threads, one row at a time. A row only
+// moves on to a pixel once the row above it is far enough ahead that every bit
+// of error for that pixel has landed, and that the row above won't touch
+// anything this row is about to write to. Each pixel then gathers its error in
+// the exact same order as the serial version, so the output is identical.
+//
+// Serpentine scanning has each row wait for most of the row above, so it gains
+// very little from this.
+
+pub fn diffuse<F>(
+    size: (usize, usize),
+    reach: usize,
+    serpentine: bool,
+    fill_index: u8,
+    diffuse_pixel: F,
+) -> Vec<u8>
+where
+    F: Fn((usize, usize), bool, image::Rgba<f32>, &mut dyn FnMut(image::Rgba<f32>, usize)) -> u8
+        + Sync,
+{
+    let (width, height) = size;
+    let error_buf = (0..width * height)
+        .map(|_| Default::default())
+        .collect::<Vec<[AtomicU32; 4]>>();
+    let indices = (0..width * height)
+        .map(|_| AtomicU8::new(fill_index))
+        .collect::<Vec<_>>();
+    let progress = (0..height).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>();
+    let next_row = AtomicUsize::new(0);
+
+    // Tasks wait on each other, but only ever on rows that a running task has
+    // already taken. A task that hasn't started yet (because the pool is busy
+    // with other bitmaps) holds nothing up, it just finds fewer rows left.
+    rayon::scope(|scope| {
+        for _ in 0..rayon::current_num_threads().min(height) {
+            scope.spawn(|_| {
+                // Rows are handed out in order, so the row above is always
+                // either done or being worked on by another task.
+                loop {
+                    let y = next_row.fetch_add(1, Ordering::Relaxed);
+
+                    if y >= height {
+                        break;
+                    }
+
+                    let reverse = serpentine && y % 2 == 1;
+                    let previous_reverse = serpentine && !reverse;
+
+                    for step in 0..width {
+                        let x = if reverse { width - 1 - step } else { step };
+
+                        if y > 0 {
+                            let first = x.saturating_sub(reach * 2);
+                            let last = (x + reach * 2).min(width - 1);
+                            wait_for(&progress[y - 1], |done| {
+                                if previous_reverse {
+                                    width - done <= first
+                                } else {
+                                    done > last
+                                }
+                            });
+                        }
+
+                        let index = y * width + x;
+                        let color_index = diffuse_pixel(
+                            (x, y),
+                            reverse,
+                            load(&error_buf[index]),
+                            &mut |value, target| add(&error_buf[target], value),
+                        );
+
+                        indices[index].store(color_index, Ordering::Relaxed);
+                        progress[y].store(step + 1, Ordering::Release);
+                    }
+                }
+            });
+        }
+    });
+
+    indices.into_iter().map(AtomicU8::into_inner).collect()
+}
+
+#[inline(always)]
+fn wait_for<P>(progress: &AtomicUsize, ready: P)
+where
+    P: Fn(usize) -> bool,
+{
+    let mut spins = 0;
+
+    while !ready(progress.load(Ordering::Acquire)) {
+        if spins < 64 {
+            std::hint::spin_loop();
+            spins += 1;
+        } else {
+            std::thread::yield_now();
+        }
+    }
+}
+
+// Only one row ever writes to a pixel at a time, so a plain load and store is
+// enough here.
+#[inline(always)]
+fn load(cell: &[AtomicU32; 4]) -> image::Rgba<f32> {
+    image::Rgba(
+        cell.each_ref()
+            .map(|v| f32::from_bits(v.load(Ordering::Relaxed))),
+    )
+}
+
+#[inline(always)]
+fn add(cell: &[AtomicU32; 4], value: image::Rgba<f32>) {
+    cell.iter().zip(value.0).for_each(|(v, value)| {
+        let old = f32::from_bits(v.load(Ordering::Relaxed));
+        v.store((old + value).to_bits(), Ordering::Relaxed);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{BorderPolicy, DitherOptions};
+
+    const KERNELS: [DitherOptions; 9] = [
+        DitherOptions::FloydSteinberg,
+        DitherOptions::JavisJudiceNinke,
+        DitherOptions::Stucki,
+        DitherOptions::Atkinson,
+        DitherOptions::Burkes,
+        DitherOptions::Sierra,
+        DitherOptions::TwoRowSierra,
+        DitherOptions::SierraLite,
+        DitherOptions::Custom,
+    ];
+
+    // Big enough to go through the wavefront, with a round silhouette so
+    // mask-aware diffusion has an edge to work along.
+    fn test_image() -> image::Rgba32FImage {
+        let size = 256;
+
+        image::Rgba32FImage::from_fn(size, size, |x, y| {
+            let (u, v) = (x as f32 / size as f32, y as f32 / size as f32);
+            let inside = (u - 0.5).powi(2) + (v - 0.5).powi(2) < 0.2;
+            image::Rgba([u, v, (u * 7.0 + v * 3.0).fract(), inside as u8 as f32])
+        })
+    }
+
+    fn quantize(
+        threads: usize,
+        image_buffer: &image::Rgba32FImage,
+        options: &crate::bgf::BitmapImageOptions,
+    ) -> Vec<u8> {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap()
+            .install(|| crate::bgf::Bitmap::quantize(image_buffer, options).unwrap())
+    }
+
+    #[test]
+    fn matches_serial() {
+        let image_buffer = test_image();
+        let kernel = crate::conf::DiffusionKernel {
+            divisor: 16.0,
+            weights: [(6.0, (1, 0)), (2.0, (3, 0)), (3.0, (-3, 1)), (5.0, (0, 2))]
+                .into_iter()
+                .map(|(weight, offset)| crate::conf::DiffusionKernelEntry { weight, offset })
+                .collect(),
+        };
+        let variants = [
+            (false, false, BorderPolicy::Drop),
+            (true, false, BorderPolicy::Drop),
+            (false, true, BorderPolicy::Drop),
+            (false, false, BorderPolicy::Clamp),
+        ];
+
+        assert!(image_buffer.pixels().len() >= super::super::WAVEFRONT_MIN_PIXELS);
+
+        for dither in KERNELS {
+            for (serpentine, mask_diffusion, border) in variants {
+                let options = crate::bgf::BitmapImageOptions {
+                    dither,
+                    serpentine,
+                    mask_diffusion,
+                    border,
+                    transparency_clip: 0.5,
+                    diffusion_kernel: Some(kernel.clone()),
+                    ..Default::default()
+                };
+
+                assert!(
+                    quantize(1, &image_buffer, &options) == quantize(4, &image_buffer, &options),
+                    "{dither:?} with serpentine {serpentine}, mask diffusion \
+                     {mask_diffusion} and {border:?} borders doesn't match"
+                );
+            }
+        }
+    }
+}
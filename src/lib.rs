@@ -1,3 +1,8 @@
+pub mod anchor;
+pub mod animate;
+pub mod aseprite;
+pub mod atlas;
 pub mod bgf;
+pub mod compose;
 pub mod conf;
 pub mod dither;
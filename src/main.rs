@@ -1,7 +1,9 @@
 use clap::Parser;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{self, Result};
 use rayon::prelude::*;
 
+const DEFAULT_TRANSPARENCY: f32 = 0.5;
+
 #[derive(Debug, clap::Parser)]
 #[command(version, about, long_about=None)]
 struct Cli {
@@ -18,16 +20,103 @@ enum Commands {
         output_dir: std::path::PathBuf,
         #[arg(long)]
         image_ext: String,
+        #[arg(long)]
+        dedupe: bool,
+        /// Pack the bitmaps into sheets instead of writing one image each.
+        #[arg(long, num_args = 0..=1, default_missing_value = "all")]
+        atlas: Option<bgftool::atlas::AtlasMode>,
+    },
+    Animate {
+        #[arg(long)]
+        input_bgf: std::path::PathBuf,
+        /// Without a group, every group is written next to this, numbered.
+        #[arg(long)]
+        output: std::path::PathBuf,
+        #[arg(long)]
+        group: Option<usize>,
+        /// Picked from the output's extension if not given.
+        #[arg(long)]
+        format: Option<bgftool::animate::AnimationFormat>,
+        #[arg(long, default_value_t = 100)]
+        delay: u16,
+        #[arg(long, default_value_t = 1)]
+        scale: u32,
+        #[arg(long, num_args = 3, value_names = ["R", "G", "B"])]
+        background: Option<Vec<u8>>,
+    },
+    Compose {
+        #[arg(long)]
+        input_bgf: std::path::PathBuf,
+        /// PATH:HOTSPOT or PATH:HOTSPOT:GROUP, for each BGF to attach.
+        #[arg(long, value_parser = parse_overlay)]
+        overlay: Vec<OverlaySpec>,
+        #[arg(long, default_value_t = 0)]
+        group: usize,
+        /// Write each frame as a PNG into this directory.
+        #[arg(long)]
+        output_dir: Option<std::path::PathBuf>,
+        /// Write the frames as a GIF, APNG or WebP animation.
+        #[arg(long)]
+        animation: Option<std::path::PathBuf>,
+        /// Picked from the animation's extension if not given.
+        #[arg(long)]
+        format: Option<bgftool::animate::AnimationFormat>,
+        #[arg(long, default_value_t = 100)]
+        delay: u16,
+        #[arg(long, default_value_t = 1)]
+        scale: u32,
+        #[arg(long, num_args = 3, value_names = ["R", "G", "B"])]
+        background: Option<Vec<u8>>,
     },
     Compile {
         #[arg(long)]
         input_conf: std::path::PathBuf,
         #[arg(long)]
         output_bgf: std::path::PathBuf,
-        #[arg(long, default_value = "none")]
-        dither: bgftool::dither::DitherOptions,
-        #[arg(long, default_value = "0.5")]
-        transparency: f32,
+        #[arg(long)]
+        dither: Option<bgftool::dither::DitherOptions>,
+        #[arg(long)]
+        ditherer: Option<String>,
+        #[arg(long)]
+        auto_metric: Option<bgftool::dither::quality::Metric>,
+        #[arg(long)]
+        dither_strength: Option<f32>,
+        #[arg(long)]
+        dither_seed: Option<u64>,
+        #[arg(long)]
+        transparency: Option<f32>,
+        #[arg(long)]
+        blue_noise_size: Option<usize>,
+        #[arg(long, num_args = 2, value_names = ["X", "Y"])]
+        blue_noise_offset: Option<Vec<usize>>,
+        #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+        serpentine: Option<bool>,
+        #[arg(long)]
+        border: Option<bgftool::dither::BorderPolicy>,
+        #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+        mask_diffusion: Option<bool>,
+        #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+        protect_exact: Option<bool>,
+        #[arg(long)]
+        diffusion_kernel: Option<std::path::PathBuf>,
+        #[arg(long)]
+        riemersma_history: Option<usize>,
+        #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+        linear_light: Option<bool>,
+        #[arg(long)]
+        alpha: Option<bgftool::dither::alpha::AlphaMode>,
+        #[arg(long, num_args = 3, value_names = ["R", "G", "B"])]
+        matte: Option<Vec<u8>>,
+        #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+        temporal: Option<bool>,
+        #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+        trim: Option<bool>,
+        #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+        dedupe: Option<bool>,
+        #[arg(long)]
+        report: Option<std::path::PathBuf>,
+        #[arg(long)]
+        preview_dir: Option<std::path::PathBuf>,
     },
 }
 
@@ -41,13 +130,117 @@ fn main() -> Result<()> {
             input_bgf,
             output_dir,
             image_ext,
-        } => decompile(&input_bgf, &output_dir, &image_ext)?,
+            dedupe,
+            atlas,
+        } => match atlas {
+            Some(mode) => decompile_atlas(&input_bgf, &output_dir, &image_ext, mode, dedupe)?,
+            None => decompile(&input_bgf, &output_dir, &image_ext, dedupe)?,
+        },
+        Commands::Animate {
+            input_bgf,
+            output,
+            group,
+            format,
+            delay,
+            scale,
+            background,
+        } => animate(
+            &input_bgf,
+            &output,
+            group,
+            format,
+            &bgftool::animate::AnimationOptions {
+                delay,
+                scale,
+                background: background.map(|v| [v[0], v[1], v[2]]),
+            },
+        )?,
+        Commands::Compose {
+            input_bgf,
+            overlay,
+            group,
+            output_dir,
+            animation,
+            format,
+            delay,
+            scale,
+            background,
+        } => compose(
+            &input_bgf,
+            &overlay,
+            group,
+            output_dir.as_deref(),
+            animation.as_deref(),
+            format,
+            &bgftool::animate::AnimationOptions {
+                delay,
+                scale,
+                background: background.map(|v| [v[0], v[1], v[2]]),
+            },
+        )?,
         Commands::Compile {
             input_conf,
             output_bgf,
             dither,
+            ditherer,
+            auto_metric,
+            dither_strength,
+            dither_seed,
             transparency,
-        } => compile(&input_conf, &output_bgf, dither, transparency)?,
+            blue_noise_size,
+            blue_noise_offset,
+            serpentine,
+            border,
+            mask_diffusion,
+            protect_exact,
+            diffusion_kernel,
+            riemersma_history,
+            linear_light,
+            alpha,
+            matte,
+            temporal,
+            trim,
+            dedupe,
+            report,
+            preview_dir,
+        } => {
+            // Anything set on the command line overrides the conf.
+            let overrides = bgftool::conf::DitherSettings {
+                algorithm: dither,
+                ditherer,
+                auto_metric,
+                strength: dither_strength,
+                seed: dither_seed,
+                transparency,
+                serpentine,
+                border,
+                mask_diffusion,
+                protect_exact,
+                blue_noise_size,
+                blue_noise_offset: blue_noise_offset.map(|v| (v[0], v[1])),
+                riemersma_history,
+                linear_light,
+                alpha,
+                matte: matte.map(|v| [v[0], v[1], v[2]]),
+            };
+            let diffusion_kernel = diffusion_kernel
+                .map(bgftool::conf::DiffusionKernel::read)
+                .transpose()?;
+
+            compile(
+                &input_conf,
+                &output_bgf,
+                CompileSettings {
+                    overrides,
+                    diffusion_kernel,
+                    temporal,
+                    trim,
+                    dedupe,
+                    report: report.as_deref(),
+                    preview_dir: preview_dir.as_deref(),
+                },
+            )?
+        }
     }
 
     Ok(())
@@ -57,15 +250,36 @@ fn decompile(
     input_bgf: &std::path::Path,
     output_dir: &std::path::Path,
     image_ext: &str,
+    dedupe: bool,
 ) -> Result<()> {
     let bgf = bgftool::bgf::Bgf::read(std::fs::File::open(input_bgf)?)?;
     let name = input_bgf.file_stem().unwrap().to_string_lossy();
     let mut image_paths = Vec::with_capacity(bgf.bitmaps.len());
+    // Bitmaps with the same pixels share one image when deduping.
+    let mut saved_images: std::collections::HashMap<_, std::path::PathBuf> =
+        std::collections::HashMap::new();
 
     for (index, bitmap) in bgf.bitmaps.iter().enumerate() {
+        let key = if dedupe {
+            let key = (bitmap.size, bitmap.indices()?.into_owned());
+
+            if let Some(output_path) = saved_images.get(&key) {
+                image_paths.push(output_path.clone());
+                continue;
+            }
+
+            Some(key)
+        } else {
+            None
+        };
+
         let output_path = output_dir.join(format!("{name}_{index:04}.{image_ext}"));
         bitmap.save_image(&output_path)?;
-        image_paths.push(output_path);
+        image_paths.push(output_path.clone());
+
+        if let Some(key) = key {
+            saved_images.insert(key, output_path);
+        }
     }
 
     let mut conf = bgftool::conf::Bgf::from(bgf);
@@ -80,47 +294,509 @@ fn decompile(
     Ok(())
 }
 
+fn decompile_atlas(
+    input_bgf: &std::path::Path,
+    output_dir: &std::path::Path,
+    image_ext: &str,
+    mode: bgftool::atlas::AtlasMode,
+    dedupe: bool,
+) -> Result<()> {
+    let bgf = bgftool::bgf::Bgf::read(std::fs::File::open(input_bgf)?)?;
+    let name = input_bgf.file_stem().unwrap().to_string_lossy();
+    // When deduping, each bitmap points at the first one with the same pixels,
+    // and only that one goes into the sheets.
+    let mut shared = (0..bgf.bitmaps.len()).collect::<Vec<_>>();
+
+    if dedupe {
+        let mut first_seen = std::collections::HashMap::new();
+
+        for (index, bitmap) in bgf.bitmaps.iter().enumerate() {
+            let key = (bitmap.size, bitmap.indices()?.into_owned());
+            shared[index] = *first_seen.entry(key).or_insert(index);
+        }
+    }
+
+    let mut sheets = match mode {
+        bgftool::atlas::AtlasMode::All => {
+            let frames = (0..bgf.bitmaps.len())
+                .filter(|i| shared[*i] == *i)
+                .collect();
+            vec![(format!("{name}_atlas"), frames)]
+        }
+        bgftool::atlas::AtlasMode::Group => bgf
+            .index_groups
+            .iter()
+            .enumerate()
+            .map(|(group_index, group)| {
+                let mut frames = Vec::new();
+
+                // Frames shown more than once only need to be in the sheet once.
+                for index in group
+                    .indices
+                    .iter()
+                    .filter_map(|i| usize::try_from(*i).ok())
+                    .filter(|i| *i < bgf.bitmaps.len())
+                    .map(|i| shared[i])
+                {
+                    if !frames.contains(&index) {
+                        frames.push(index);
+                    }
+                }
+
+                (format!("{name}_group{group_index:04}"), frames)
+            })
+            .collect::<Vec<(String, Vec<usize>)>>(),
+    };
+
+    // Bitmaps that aren't in any group still need to go somewhere.
+    let ungrouped = (0..bgf.bitmaps.len())
+        .filter(|i| shared[*i] == *i)
+        .filter(|i| !sheets.iter().any(|(_, frames)| frames.contains(i)))
+        .collect::<Vec<_>>();
+
+    if !ungrouped.is_empty() {
+        sheets.push((format!("{name}_ungrouped"), ungrouped));
+    }
+
+    let mut rects = vec![None; bgf.bitmaps.len()];
+
+    for (sheet_name, frames) in sheets {
+        let bitmaps = frames.iter().map(|i| &bgf.bitmaps[*i]).collect::<Vec<_>>();
+        let names = frames
+            .iter()
+            .map(|i| format!("{name}_{i:04}"))
+            .collect::<Vec<_>>();
+        let image_name = format!("{sheet_name}.{image_ext}");
+        let (sheet, manifest) = bgftool::atlas::build(&bitmaps, &names, &image_name)?;
+
+        for (index, frame_name) in frames.iter().zip(&names) {
+            let frame = manifest.frame(frame_name).unwrap();
+            rects[*index].get_or_insert((image_name.clone(), frame.frame));
+        }
+
+        sheet.save(output_dir.join(&image_name))?;
+        serde_json::to_writer_pretty(
+            std::fs::File::create(output_dir.join(format!("{sheet_name}.json")))?,
+            &manifest,
+        )?;
+    }
+
+    // A conf that compiles straight from the sheets.
+    let mut conf = bgftool::conf::Bgf::from(bgf);
+
+    for (index, bitmap) in conf.bitmaps.iter_mut().enumerate() {
+        let (image_name, rect) = rects[shared[index]].clone().unwrap();
+        bitmap.path = image_name.into();
+        bitmap.rect = Some(rect);
+    }
+
+    let conf_path = output_dir.join(format!("{name}.json"));
+    serde_json::to_writer_pretty(std::fs::File::create(&conf_path)?, &conf)?;
+
+    Ok(())
+}
+
+fn animate(
+    input_bgf: &std::path::Path,
+    output: &std::path::Path,
+    group: Option<usize>,
+    format: Option<bgftool::animate::AnimationFormat>,
+    options: &bgftool::animate::AnimationOptions,
+) -> Result<()> {
+    let bgf = bgftool::bgf::Bgf::read(std::fs::File::open(input_bgf)?)?;
+    let format = format
+        .or_else(|| bgftool::animate::AnimationFormat::from_path(output))
+        .ok_or_else(|| {
+            eyre::eyre!(
+                "Can't tell the animation format from {}, so it needs --format.",
+                output.display()
+            )
+        })?;
+    let outputs = match group {
+        Some(group) => vec![(group, output.to_path_buf())],
+        None => {
+            let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = output.extension().unwrap_or_default().to_string_lossy();
+
+            (0..bgf.index_groups.len())
+                .map(|group| {
+                    (
+                        group,
+                        output.with_file_name(format!("{stem}_{group:04}.{extension}")),
+                    )
+                })
+                .collect()
+        }
+    };
+
+    for (group, output) in outputs {
+        let index_group = bgf
+            .index_groups
+            .get(group)
+            .ok_or_else(|| eyre::eyre!("There is no group {group}."))?;
+        let frames = bgftool::animate::render_group(&bgf, index_group, options)?;
+
+        bgftool::animate::write(
+            std::io::BufWriter::new(std::fs::File::create(output)?),
+            &frames,
+            options.delay,
+            format,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+struct OverlaySpec {
+    path: std::path::PathBuf,
+    hotspot: i8,
+    group: usize,
+}
+
+fn parse_overlay(value: &str) -> Result<OverlaySpec> {
+    let parts = value.rsplitn(3, ':').collect::<Vec<_>>();
+    let (path, hotspot, group) = match parts[..] {
+        [hotspot, path] => (path, hotspot, "0"),
+        [group, hotspot, path] if hotspot.parse::<i8>().is_ok() => (path, hotspot, group),
+        // The last part is the hotspot, and the path has a colon in it.
+        [hotspot, rest, path] => (&value[..path.len() + rest.len() + 1], hotspot, "0"),
+        _ => return Err(eyre::eyre!("Overlays are written as PATH:HOTSPOT[:GROUP].")),
+    };
+
+    Ok(OverlaySpec {
+        path: path.into(),
+        hotspot: hotspot.parse()?,
+        group: group.parse()?,
+    })
+}
+
+fn compose(
+    input_bgf: &std::path::Path,
+    overlay_specs: &[OverlaySpec],
+    group: usize,
+    output_dir: Option<&std::path::Path>,
+    animation: Option<&std::path::Path>,
+    format: Option<bgftool::animate::AnimationFormat>,
+    options: &bgftool::animate::AnimationOptions,
+) -> Result<()> {
+    if output_dir.is_none() && animation.is_none() {
+        return Err(eyre::eyre!(
+            "Nothing to write, so give --output-dir or --animation."
+        ));
+    }
+
+    let base = bgftool::bgf::Bgf::read(std::fs::File::open(input_bgf)?)?;
+    let overlay_bgfs = overlay_specs
+        .iter()
+        .map(|spec| bgftool::bgf::Bgf::read(std::fs::File::open(&spec.path)?))
+        .collect::<Result<Vec<_>>>()?;
+    let overlays = overlay_specs
+        .iter()
+        .zip(&overlay_bgfs)
+        .map(|(spec, bgf)| bgftool::compose::Overlay {
+            bgf,
+            hotspot: spec.hotspot,
+            group: spec.group,
+        })
+        .collect::<Vec<_>>();
+    let index_group = base
+        .index_groups
+        .get(group)
+        .ok_or_else(|| eyre::eyre!("There is no group {group}."))?;
+    let frames = bgftool::compose::compose(&base, index_group, &overlays, options)?;
+
+    if let Some(output_dir) = output_dir {
+        let name = input_bgf.file_stem().unwrap().to_string_lossy();
+        std::fs::create_dir_all(output_dir)?;
+
+        for (index, frame) in frames.iter().enumerate() {
+            frame.save(output_dir.join(format!("{name}_{index:04}.png")))?;
+        }
+    }
+
+    if let Some(animation) = animation {
+        let format = format
+            .or_else(|| bgftool::animate::AnimationFormat::from_path(animation))
+            .ok_or_else(|| {
+                eyre::eyre!(
+                    "Can't tell the animation format from {}, so it needs --format.",
+                    animation.display()
+                )
+            })?;
+
+        bgftool::animate::write(
+            std::io::BufWriter::new(std::fs::File::create(animation)?),
+            &frames,
+            options.delay,
+            format,
+        )?;
+    }
+
+    Ok(())
+}
+
+// Write the dithered result, a heatmap of the error, and the source next to
+// the result, all as PNGs starting with `prefix`.
+fn write_previews(
+    prefix: &std::path::Path,
+    image_buffer: &image::Rgba32FImage,
+    indices: &[u8],
+) -> Result<()> {
+    let palette = bgftool::bgf::Palette::new();
+    let (width, height) = (image_buffer.width(), image_buffer.height());
+    let quantized =
+        bgftool::bgf::Bitmap::from_indices((width, height), indices.to_vec(), Default::default())?
+            .to_image()?;
+    let heatmap = bgftool::dither::quality::error_heatmap(image_buffer, indices, &palette);
+
+    // Show the source over the transparent color, so it lines up with how the
+    // result looks.
+    let (_, transparent_color) = palette.transparent_color();
+    let mut comparison = image::RgbImage::new(width * 2, height);
+
+    for (x, y, pixel) in image_buffer.enumerate_pixels() {
+        let alpha = pixel[3].clamp(0.0, 1.0);
+        let source = image::Rgb([0, 1, 2].map(|i| {
+            let value = pixel[i].clamp(0.0, 1.0) * alpha
+                + transparent_color[i] as f32 / 255.0 * (1.0 - alpha);
+            (value * 255.0).round() as u8
+        }));
+
+        comparison.put_pixel(x, y, source);
+        comparison.put_pixel(x + width, y, *quantized.get_pixel(x, y));
+    }
+
+    let path = |suffix: &str| {
+        let mut path = prefix.as_os_str().to_owned();
+        path.push(format!("_{suffix}.png"));
+        std::path::PathBuf::from(path)
+    };
+
+    quantized.save(path("quantized"))?;
+    heatmap.save(path("error"))?;
+    comparison.save(path("compare"))?;
+
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct BitmapReport {
+    index: usize,
+    path: std::path::PathBuf,
+    dither: bgftool::dither::DitherOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ditherer: Option<String>,
+    metrics: bgftool::dither::quality::Metrics,
+}
+
+#[derive(serde::Serialize)]
+struct GroupReport {
+    index: usize,
+    static_flicker: f32,
+    flicker: bgftool::dither::temporal::Flicker,
+}
+
+#[derive(serde::Serialize)]
+struct CompileReport {
+    bitmaps: Vec<BitmapReport>,
+    // Only there for groups dithered temporally.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    groups: Vec<GroupReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dedupe: Option<bgftool::bgf::Dedupe>,
+}
+
+// Everything from the command line that changes how the conf is compiled.
+struct CompileSettings<'a> {
+    overrides: bgftool::conf::DitherSettings,
+    diffusion_kernel: Option<bgftool::conf::DiffusionKernel>,
+    temporal: Option<bool>,
+    trim: Option<bool>,
+    dedupe: Option<bool>,
+    report: Option<&'a std::path::Path>,
+    preview_dir: Option<&'a std::path::Path>,
+}
+
 fn compile(
     input_conf: &std::path::Path,
     output_bgf: &std::path::Path,
-    dither: bgftool::dither::DitherOptions,
-    transparency: f32,
+    settings: CompileSettings,
 ) -> Result<()> {
+    let CompileSettings {
+        overrides,
+        diffusion_kernel,
+        temporal,
+        trim,
+        dedupe,
+        report,
+        preview_dir,
+    } = settings;
     let input_conf = input_conf.canonicalize()?;
     let input_conf_dir = input_conf.parent().unwrap();
-    let conf: bgftool::conf::Bgf = serde_json::from_reader(std::fs::File::open(&input_conf)?)?;
+    // An Aseprite document can be compiled on its own, as if it were the only
+    // sheet in a conf.
+    let mut conf: bgftool::conf::Bgf = if bgftool::aseprite::is_aseprite(&input_conf) {
+        bgftool::conf::Bgf::from_sheet(bgftool::conf::Sheet::new(
+            input_conf.file_name().unwrap().into(),
+        ))
+    } else {
+        serde_json::from_reader(std::fs::File::open(&input_conf)?)?
+    };
+    conf.expand_sheets(input_conf_dir)?;
+    let default_dither = conf.dither.clone().unwrap_or_default();
+    let default_options = bgftool::bgf::BitmapImageOptions {
+        transparency_clip: DEFAULT_TRANSPARENCY,
+        diffusion_kernel: diffusion_kernel.or(conf.diffusion_kernel.clone()),
+        ..Default::default()
+    };
 
-    let bitmap_results = conf
+    let mut options = conf
         .bitmaps
-        .into_par_iter()
-        .map(|bitmap_conf| -> Result<bgftool::bgf::Bitmap> {
-            let bitmap_path = input_conf_dir.join(bitmap_conf.path);
-            let options = bgftool::bgf::BitmapImageOptions {
+        .iter()
+        .map(|bitmap_conf| {
+            let mut options = bgftool::bgf::BitmapImageOptions {
                 compression: bitmap_conf.compression,
-                transparency_clip: transparency,
-                dither,
+                ..default_options.clone()
             };
-            let mut bitmap = bgftool::bgf::Bitmap::from_image(bitmap_path, &options)?;
-            bitmap.offset = bitmap_conf.offset;
-            bitmap.hotspots = bitmap_conf
-                .hotspots
-                .into_iter()
-                .map(|h| bgftool::bgf::Hotspot {
-                    number: h.number,
-                    position: bgftool::bgf::Point(h.position.0, h.position.1),
-                })
-                .collect();
+            overrides
+                .or(bitmap_conf.dither.as_ref().unwrap_or(&Default::default()))
+                .or(&default_dither)
+                .apply(&mut options)?;
 
-            Ok(bitmap)
+            Ok(options)
         })
+        .collect::<Result<Vec<_>>>()?;
+    let (mut images, mut verbatim_indices, mut hotspot_layers) =
+        load_images(&conf, input_conf_dir)?;
+    let hotspots = extract_hotspots(
+        &conf,
+        input_conf_dir,
+        &mut images,
+        &mut verbatim_indices,
+        &mut hotspot_layers,
+    )?;
+    let offsets = resolve_anchors(&conf, &mut images, &mut verbatim_indices, &options)?;
+
+    for ((bitmap_conf, options), image_buffer) in conf.bitmaps.iter().zip(&mut options).zip(&images)
+    {
+        if let Some(mask_path) = &bitmap_conf.dither_mask {
+            let mask = image::open(input_conf_dir.join(mask_path))?.into_luma8();
+
+            if mask.dimensions() != image_buffer.dimensions() {
+                return Err(eyre::eyre!(
+                    "Dither mask {} is {}x{}, but the bitmap is {}x{}.",
+                    mask_path.display(),
+                    mask.width(),
+                    mask.height(),
+                    image_buffer.width(),
+                    image_buffer.height()
+                ));
+            }
+
+            options.dither_mask = Some(mask.pixels().map(|p| p[0] >= 128).collect());
+        }
+    }
+
+    // Pick the dither up front, so frames dithered together below use the
+    // same one for every pass.
+    options
+        .par_iter_mut()
+        .zip(&images)
+        .try_for_each(|(options, image_buffer)| -> Result<()> {
+            if options.dither == bgftool::dither::DitherOptions::Auto && options.ditherer.is_none()
+            {
+                (options.dither, _) = bgftool::dither::quality::best_dither(
+                    image_buffer,
+                    options,
+                    options.auto_metric,
+                )?;
+            }
+
+            Ok(())
+        })?;
+
+    if let Some(preview_dir) = preview_dir {
+        std::fs::create_dir_all(preview_dir)?;
+    }
+
+    // Anything already in the BGF palette skips dithering.
+    let mut indices = verbatim_indices;
+
+    let trim = trim.unwrap_or(conf.trim);
+    let dedupe = dedupe.unwrap_or(conf.dedupe);
+
+    let group_reports = if temporal.unwrap_or(conf.temporal) {
+        dither_groups_temporally(
+            &conf.index_groups,
+            &images,
+            &offsets,
+            &options,
+            &mut indices,
+        )?
+    } else {
+        Vec::new()
+    };
+
+    let bitmap_results = conf
+        .bitmaps
+        .into_par_iter()
+        .zip(indices)
+        .zip(hotspots)
+        .enumerate()
+        .map(
+            |(index, ((bitmap_conf, buf), hotspots))| -> Result<(bgftool::bgf::Bitmap, Option<BitmapReport>)> {
+                let image_buffer = &images[index];
+                let buf = match buf {
+                    Some(buf) => buf,
+                    None => bgftool::bgf::Bitmap::quantize(image_buffer, &options[index])?,
+                };
+                let bitmap_report = report.is_some().then(|| BitmapReport {
+                    index,
+                    path: bitmap_conf.path.clone(),
+                    dither: options[index].dither,
+                    ditherer: options[index].ditherer.clone(),
+                    metrics: bgftool::dither::quality::measure(
+                        image_buffer,
+                        &buf,
+                        &bgftool::bgf::Palette::new(),
+                    ),
+                });
+
+                if let Some(preview_dir) = preview_dir {
+                    let name = output_bgf.file_stem().unwrap().to_string_lossy();
+                    write_previews(
+                        &preview_dir.join(format!("{name}_{index:04}")),
+                        image_buffer,
+                        &buf,
+                    )?;
+                }
+                let mut bitmap = bgftool::bgf::Bitmap::from_indices(
+                    (image_buffer.width(), image_buffer.height()),
+                    buf,
+                    bitmap_conf.compression,
+                )?;
+                bitmap.offset = offsets[index];
+                bitmap.hotspots = hotspots;
+
+                if trim {
+                    bitmap.trim()?;
+                }
+
+                Ok((bitmap, bitmap_report))
+            },
+        )
         .collect::<Vec<_>>();
     let mut bitmaps = Vec::with_capacity(bitmap_results.len());
+    let mut bitmap_reports = Vec::with_capacity(bitmap_results.len());
 
     for bitmap_result in bitmap_results {
-        bitmaps.push(bitmap_result?);
+        let (bitmap, bitmap_report) = bitmap_result?;
+        bitmaps.push(bitmap);
+        bitmap_reports.extend(bitmap_report);
     }
 
-    let bgf = bgftool::bgf::Bgf {
+    let mut bgf = bgftool::bgf::Bgf {
         version: conf.version,
         name: conf.name,
         bitmaps,
@@ -131,7 +807,508 @@ fn compile(
             .collect(),
         shrink_factor: conf.shrink_factor,
     };
+
+    let dedupe_report = if dedupe {
+        let dedupe = bgf.dedupe()?;
+        println!(
+            "Removed {} duplicate bitmaps, saving {} bytes",
+            dedupe.removed, dedupe.bytes_saved
+        );
+        Some(dedupe)
+    } else {
+        None
+    };
+
+    if let Some(report) = report {
+        serde_json::to_writer_pretty(
+            std::fs::File::create(report)?,
+            &CompileReport {
+                bitmaps: bitmap_reports,
+                groups: group_reports,
+                dedupe: dedupe_report,
+            },
+        )?;
+    }
+
     bgf.write(std::fs::File::create(output_bgf)?)?;
 
     Ok(())
 }
+
+// Where the pixels of a bitmap come from. Aseprite documents are drawn a frame
+// at a time.
+enum Source {
+    Image(image::Rgba32FImage),
+    Animation(Vec<image::Rgba32FImage>),
+    Aseprite(bgftool::aseprite::Document),
+}
+
+type LoadedImages = (
+    Vec<image::Rgba32FImage>,
+    Vec<Option<Vec<u8>>>,
+    Vec<Option<image::Rgba32FImage>>,
+);
+
+// Open every bitmap's image, cut down to its rect. Along with the images come
+// the palette indices of bitmaps that are already in the BGF palette, and the
+// hotspot layer of bitmaps from Aseprite documents.
+fn load_images(
+    conf: &bgftool::conf::Bgf,
+    input_conf_dir: &std::path::Path,
+) -> Result<LoadedImages> {
+    // Bitmaps cut from the same sheet share the image, so only open it once.
+    let mut paths = conf
+        .bitmaps
+        .iter()
+        .map(|bitmap_conf| (&bitmap_conf.path, bitmap_conf.frame.is_some()))
+        .collect::<Vec<_>>();
+    paths.sort();
+    // Only bother decoding every frame if some bitmap asks for one.
+    paths.dedup_by(|(path, frames), (kept_path, kept_frames)| {
+        let same = path == kept_path;
+        *kept_frames |= same && *frames;
+        same
+    });
+    let sources = paths
+        .into_par_iter()
+        .map(|(path, frames)| -> Result<_> {
+            let full_path = input_conf_dir.join(path);
+            let source = if bgftool::aseprite::is_aseprite(path) {
+                Source::Aseprite(bgftool::aseprite::Document::open(full_path)?)
+            } else if frames {
+                Source::Animation(bgftool::animate::read_frames(&full_path)?)
+            } else {
+                Source::Image(bgftool::bgf::Bitmap::open_image(full_path)?)
+            };
+
+            Ok((path, source))
+        })
+        .collect::<Result<std::collections::HashMap<_, _>>>()?;
+    let mut images = Vec::with_capacity(conf.bitmaps.len());
+    let mut verbatim_indices = Vec::with_capacity(conf.bitmaps.len());
+    let mut hotspot_layers = Vec::with_capacity(conf.bitmaps.len());
+
+    for bitmap_conf in &conf.bitmaps {
+        let (image_buffer, indices, hotspot_layer) = match &sources[&bitmap_conf.path] {
+            Source::Image(image_buffer) => (image_buffer.clone(), None, None),
+            Source::Animation(frames) => {
+                let frame = bitmap_conf.frame.unwrap_or_default();
+                let image_buffer = frames.get(frame).ok_or_else(|| {
+                    eyre::eyre!("{} has no frame {}.", bitmap_conf.path.display(), frame)
+                })?;
+
+                (image_buffer.clone(), None, None)
+            }
+            Source::Aseprite(document) => {
+                let frame = bitmap_conf.frame.unwrap_or_default();
+
+                if frame >= document.frames.len() {
+                    return Err(eyre::eyre!(
+                        "{} has no frame {}.",
+                        bitmap_conf.path.display(),
+                        frame
+                    ));
+                }
+
+                let hotspot_layer = match &bitmap_conf.hotspot_layer {
+                    Some(name) => Some(document.layer_index(name).ok_or_else(|| {
+                        eyre::eyre!(
+                            "{} has no layer called \"{}\".",
+                            bitmap_conf.path.display(),
+                            name
+                        )
+                    })?),
+                    None => None,
+                };
+
+                (
+                    document.render(frame, hotspot_layer),
+                    document.render_indices(frame, hotspot_layer),
+                    hotspot_layer.map(|layer| document.render_layer(frame, layer)),
+                )
+            }
+        };
+
+        let Some(rect) = bitmap_conf.rect else {
+            images.push(image_buffer);
+            verbatim_indices.push(indices);
+            hotspot_layers.push(hotspot_layer);
+            continue;
+        };
+
+        if rect.x + rect.w > image_buffer.width() || rect.y + rect.h > image_buffer.height() {
+            return Err(eyre::eyre!(
+                "Rect {}x{} at ({}, {}) doesn't fit in {}, which is {}x{}.",
+                rect.w,
+                rect.h,
+                rect.x,
+                rect.y,
+                bitmap_conf.path.display(),
+                image_buffer.width(),
+                image_buffer.height()
+            ));
+        }
+
+        let crop = |image_buffer: &image::Rgba32FImage| {
+            image::imageops::crop_imm(image_buffer, rect.x, rect.y, rect.w, rect.h).to_image()
+        };
+        let width = image_buffer.width() as usize;
+
+        images.push(crop(&image_buffer));
+        verbatim_indices.push(indices.map(|indices| {
+            (rect.y as usize..(rect.y + rect.h) as usize)
+                .flat_map(|y| {
+                    let start = y * width + rect.x as usize;
+                    indices[start..start + rect.w as usize].to_vec()
+                })
+                .collect()
+        }));
+        hotspot_layers.push(hotspot_layer.as_ref().map(crop));
+    }
+
+    Ok((images, verbatim_indices, hotspot_layers))
+}
+
+// Hotspots from the conf, plus any found from marker colors. Markers are looked
+// for in the bitmap's hotspot image if it has one, and otherwise taken out of
+// the bitmap's own image. Found hotspots replace ones with the same number.
+fn extract_hotspots(
+    conf: &bgftool::conf::Bgf,
+    input_conf_dir: &std::path::Path,
+    images: &mut [image::Rgba32FImage],
+    verbatim_indices: &mut [Option<Vec<u8>>],
+    hotspot_layers: &mut [Option<image::Rgba32FImage>],
+) -> Result<Vec<Vec<bgftool::bgf::Hotspot>>> {
+    conf.bitmaps
+        .iter()
+        .zip(images.iter_mut())
+        .zip(verbatim_indices)
+        .zip(hotspot_layers)
+        .map(|(((bitmap_conf, image_buffer), indices), hotspot_layer)| {
+            let mut hotspot_image = match &bitmap_conf.hotspot_image {
+                Some(path) => {
+                    let hotspot_image =
+                        bgftool::bgf::Bitmap::open_image(input_conf_dir.join(path))?;
+
+                    if hotspot_image.dimensions() != image_buffer.dimensions() {
+                        return Err(eyre::eyre!(
+                            "Hotspot image {} is {}x{}, but the bitmap is {}x{}.",
+                            path.display(),
+                            hotspot_image.width(),
+                            hotspot_image.height(),
+                            image_buffer.width(),
+                            image_buffer.height()
+                        ));
+                    }
+
+                    Some(hotspot_image)
+                }
+                None => hotspot_layer.take(),
+            };
+            let mut hotspots = bitmap_conf
+                .hotspots
+                .iter()
+                .map(|h| bgftool::bgf::Hotspot {
+                    number: h.number,
+                    position: bgftool::bgf::Point(h.position.0, h.position.1),
+                })
+                .collect::<Vec<_>>();
+
+            // The bitmap's own markers go last, so they win over the shared ones.
+            for marker in conf
+                .hotspot_markers
+                .iter()
+                .chain(&bitmap_conf.hotspot_markers)
+            {
+                let found = match hotspot_image.as_mut() {
+                    Some(hotspot_image) => {
+                        bgftool::anchor::take_marker(hotspot_image, None, marker.color)
+                    }
+                    None => bgftool::anchor::take_marker(
+                        image_buffer,
+                        indices.as_deref_mut(),
+                        marker.color,
+                    ),
+                };
+                let Some((x, y)) = found else {
+                    continue;
+                };
+
+                hotspots.retain(|h| h.number != marker.number);
+                hotspots.push(bgftool::bgf::Hotspot {
+                    number: marker.number,
+                    position: bgftool::bgf::Point(x, y),
+                });
+            }
+
+            Ok(hotspots)
+        })
+        .collect()
+}
+
+// Work out each bitmap's offset from its anchor, falling back to the offset in
+// the conf. Frames that share a group and an anchor use the same area for it,
+// so they stay lined up with each other. Marker pixels are taken out of the
+// images (and any indices read in as they are) here, before they are dithered.
+fn resolve_anchors(
+    conf: &bgftool::conf::Bgf,
+    images: &mut [image::Rgba32FImage],
+    verbatim_indices: &mut [Option<Vec<u8>>],
+    options: &[bgftool::bgf::BitmapImageOptions],
+) -> Result<Vec<(i32, i32)>> {
+    let anchors = conf
+        .bitmaps
+        .iter()
+        .map(|bitmap_conf| bitmap_conf.anchor.or(conf.anchor))
+        .collect::<Vec<_>>();
+    // Bitmaps that aren't in any group are a group of their own.
+    let groups = (0..conf.bitmaps.len())
+        .map(|i| {
+            conf.index_groups
+                .iter()
+                .position(|group| group.indices.contains(&(i as i32)))
+                .unwrap_or(conf.index_groups.len() + i)
+        })
+        .collect::<Vec<_>>();
+    // Anchors are found in the frame each bitmap was cropped out of, so cropped
+    // frames line up with each other once the crop is put back.
+    let origins = conf
+        .bitmaps
+        .iter()
+        .map(|bitmap_conf| bitmap_conf.origin.unwrap_or_default())
+        .collect::<Vec<_>>();
+    let markers = anchors
+        .iter()
+        .zip(images.iter_mut())
+        .zip(verbatim_indices.iter_mut())
+        .zip(&origins)
+        .map(|(((anchor, image_buffer), indices), origin)| match anchor {
+            Some(bgftool::conf::Anchor::Marker(color)) => {
+                bgftool::anchor::take_marker(image_buffer, indices.as_deref_mut(), *color)
+                    .map(|(x, y)| (origin.0 + x, origin.1 + y))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let mut shared = std::collections::HashMap::new();
+    let mut offsets = Vec::with_capacity(conf.bitmaps.len());
+
+    for (index, bitmap_conf) in conf.bitmaps.iter().enumerate() {
+        let Some(anchor) = anchors[index] else {
+            offsets.push(bitmap_conf.offset);
+            continue;
+        };
+        let frames = (0..conf.bitmaps.len())
+            .filter(|i| groups[*i] == groups[index] && anchors[*i] == Some(anchor))
+            .collect::<Vec<_>>();
+        let position = match anchor {
+            bgftool::conf::Anchor::Pivot(position) => Some(position),
+            // Frames without the marker use the one from the rest of the
+            // group.
+            bgftool::conf::Anchor::Marker(color) => Some(
+                markers[index]
+                    .or_else(|| frames.iter().find_map(|i| markers[*i]))
+                    .ok_or_else(|| {
+                        eyre::eyre!(
+                            "Marker color {:?} wasn't found in {} or any frame grouped with it.",
+                            color,
+                            bitmap_conf.path.display()
+                        )
+                    })?,
+            ),
+            bgftool::conf::Anchor::BottomCenter | bgftool::conf::Anchor::Feet => {
+                *shared.entry((groups[index], anchor)).or_insert_with(|| {
+                    let frame_images = frames
+                        .iter()
+                        .map(|i| (&images[*i], origins[*i]))
+                        .collect::<Vec<_>>();
+                    let transparency_clip = options[index].transparency_clip;
+
+                    if anchor == bgftool::conf::Anchor::Feet {
+                        bgftool::anchor::feet(&frame_images, transparency_clip)
+                    } else {
+                        bgftool::anchor::bottom_center(&frame_images, transparency_clip)
+                    }
+                })
+            }
+        };
+
+        // Nothing opaque to anchor to, so keep the offset as it is.
+        offsets.push(position.map_or(bitmap_conf.offset, |(x, y)| {
+            (origins[index].0 - x, origins[index].1 - y)
+        }));
+    }
+
+    Ok(offsets)
+}
+
+// Dither the frames of each index group in playback order, locking pixels that
+// didn't change since the previous frame and anchoring noise to the group's
+// canvas. Bitmaps that appear in more than one group are only dithered for the
+// first one. Returns how much each group flickers.
+fn dither_groups_temporally(
+    index_groups: &[bgftool::conf::Group],
+    images: &[image::Rgba32FImage],
+    offsets: &[(i32, i32)],
+    options: &[bgftool::bgf::BitmapImageOptions],
+    indices: &mut [Option<Vec<u8>>],
+) -> Result<Vec<GroupReport>> {
+    let mut group_reports = Vec::new();
+
+    for (group_index, group) in index_groups.iter().enumerate() {
+        let frames = group
+            .indices
+            .iter()
+            .filter_map(|i| usize::try_from(*i).ok())
+            .filter(|i| *i < images.len())
+            .collect::<Vec<_>>();
+
+        // Single frames gain nothing here, so leave them to be dithered
+        // independently.
+        if frames.len() < 2 {
+            continue;
+        }
+
+        let canvas_min = frames.iter().fold((i32::MAX, i32::MAX), |acc, i| {
+            (acc.0.min(offsets[*i].0), acc.1.min(offsets[*i].1))
+        });
+        let canvas_max = frames.iter().fold((i32::MIN, i32::MIN), |acc, i| {
+            (
+                acc.0.max(offsets[*i].0 + images[*i].width() as i32),
+                acc.1.max(offsets[*i].1 + images[*i].height() as i32),
+            )
+        });
+        let canvas_size = (
+            (canvas_max.0 - canvas_min.0) as u32,
+            (canvas_max.1 - canvas_min.1) as u32,
+        );
+        let mut previous: Option<usize> = None;
+        let mut flicker = bgftool::dither::temporal::Flicker::default();
+
+        for &frame in &frames {
+            if indices[frame].is_none() {
+                let mut frame_options = bgftool::bgf::BitmapImageOptions {
+                    canvas_origin: (
+                        (offsets[frame].0 - canvas_min.0) as usize,
+                        (offsets[frame].1 - canvas_min.1) as usize,
+                    ),
+                    canvas_size: Some(canvas_size),
+                    ..options[frame].clone()
+                };
+
+                if let Some(previous) = previous {
+                    let previous_frame = bgftool::dither::temporal::Frame {
+                        image: &images[previous],
+                        offset: offsets[previous],
+                        indices: indices[previous].as_deref().unwrap(),
+                    };
+                    frame_options.locked_indices = Some(bgftool::dither::temporal::locked_indices(
+                        &images[frame],
+                        offsets[frame],
+                        &previous_frame,
+                    ));
+                }
+
+                indices[frame] = Some(bgftool::bgf::Bitmap::quantize(
+                    &images[frame],
+                    &frame_options,
+                )?);
+            }
+
+            if let Some(previous) = previous {
+                flicker.add(
+                    &bgftool::dither::temporal::Frame {
+                        image: &images[previous],
+                        offset: offsets[previous],
+                        indices: indices[previous].as_deref().unwrap(),
+                    },
+                    &bgftool::dither::temporal::Frame {
+                        image: &images[frame],
+                        offset: offsets[frame],
+                        indices: indices[frame].as_deref().unwrap(),
+                    },
+                );
+            }
+
+            previous = Some(frame);
+        }
+
+        group_reports.push(GroupReport {
+            index: group_index,
+            static_flicker: flicker.static_flicker(),
+            flicker,
+        });
+    }
+
+    Ok(group_reports)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn anchored_slices_stay_registered() {
+        let dir = std::path::Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/animate"
+        ));
+
+        // The frames' opaque area spans x 0 to 10 and ends at y 6, where x 2
+        // to 5 is opaque in the first frame and x 8 to 10 in the others.
+        for (anchor, position) in [
+            (bgftool::conf::Anchor::BottomCenter, (5, 6)),
+            (bgftool::conf::Anchor::Feet, (7, 6)),
+        ] {
+            let mut sheet = bgftool::conf::Sheet::new("disposal.gif".into());
+            sheet.anchor = Some(anchor);
+            let mut conf = bgftool::conf::Bgf::from_sheet(sheet);
+            conf.expand_sheets(dir).unwrap();
+            let (mut images, mut verbatim_indices, _) = super::load_images(&conf, dir).unwrap();
+            let options = vec![
+                bgftool::bgf::BitmapImageOptions {
+                    transparency_clip: super::DEFAULT_TRANSPARENCY,
+                    ..Default::default()
+                };
+                images.len()
+            ];
+
+            let offsets =
+                super::resolve_anchors(&conf, &mut images, &mut verbatim_indices, &options)
+                    .unwrap();
+
+            // Every frame is placed so the same point of the canvas ends up at
+            // the object's position, wherever it was cropped.
+            assert_eq!(offsets.len(), 3);
+            for (bitmap_conf, offset) in conf.bitmaps.iter().zip(&offsets) {
+                let origin = bitmap_conf.origin.unwrap();
+
+                assert_eq!((origin.0 - offset.0, origin.1 - offset.1), position);
+            }
+        }
+    }
+
+    #[test]
+    fn anchored_trimmed_frames_stay_registered() {
+        let dir =
+            std::path::Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/atlas"));
+        let mut sheet = bgftool::conf::Sheet::new("trimmed.png".into());
+        sheet.manifest = Some("trimmed.json".into());
+        sheet.anchor = Some(bgftool::conf::Anchor::BottomCenter);
+        let mut conf = bgftool::conf::Bgf::from_sheet(sheet);
+        conf.expand_sheets(dir).unwrap();
+        let (mut images, mut verbatim_indices, _) = super::load_images(&conf, dir).unwrap();
+        let options = vec![
+            bgftool::bgf::BitmapImageOptions {
+                transparency_clip: super::DEFAULT_TRANSPARENCY,
+                ..Default::default()
+            };
+            images.len()
+        ];
+
+        let offsets =
+            super::resolve_anchors(&conf, &mut images, &mut verbatim_indices, &options).unwrap();
+
+        // Untrimmed, the frames cover x 0 to 4 and end at y 3, so both are
+        // placed around (2, 3) of their source.
+        assert_eq!(offsets, [(1, -3), (-2, -1)]);
+    }
+}